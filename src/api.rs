@@ -0,0 +1,206 @@
+use std::{net::SocketAddr, sync::RwLock};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::{
+    control::ControlBlock,
+    core::{biz::{self, FileInfo}, client::{ClientConfig, Context}, endpoint::Endpoint, types::FileId},
+    file::{download::{self, OverwritePolicy}, info::{self, FileDetails}, transfer::Transfer, upload},
+    user::{authorization::{self, current, KeepAlive}, login, session},
+};
+
+/// The login session of a `Client`; the token is the default one while logged out.
+#[derive(Debug, Default)]
+struct State {
+    user_name: Option<String>,
+    block: ControlBlock,
+}
+
+/// Typed entry point to the file server: owns its config, the login session and the pooled
+/// server connections, and keeps the token fresh while transfers run.
+///
+/// Clients are independent, e.g. one per profile. Transfers still running when a client is
+/// dropped keep its connections until they finish. Only the global bandwidth limit is shared.
+#[derive(Debug)]
+pub struct Client {
+    ctx: Context,
+    state: RwLock<State>,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Client {
+        Client { ctx: Context::new(config), state: RwLock::new(State::default()) }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        self.ctx.config()
+    }
+
+    /// Picks up the saved session of the configured profile if the server still accepts it,
+    /// returning the user name.
+    pub async fn restore_session(&self) -> Option<String> {
+        let session = session::restore(&self.ctx).await?;
+        let mut state = self.state.write().unwrap();
+        state.user_name = Some(session.user_name.clone());
+        state.block = session.block;
+        Some(session.user_name)
+    }
+
+    pub fn user_name(&self) -> Option<String> {
+        self.state.read().unwrap().user_name.clone()
+    }
+
+    /// The newest token, which transfers may have refreshed since the last login.
+    pub fn block(&self) -> ControlBlock {
+        current(&self.ctx, &self.state.read().unwrap().block)
+    }
+
+    /// Logs in and saves the session for the configured profile.
+    pub async fn login(&self, user_name: &str, password: &Zeroizing<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut block = ControlBlock::default();
+        login::login(&self.ctx, &mut block, user_name.to_string(), password).await?;
+        self.set_session(user_name, block);
+        Ok(())
+    }
+
+    /// Registers a new user, who is logged in afterwards.
+    pub async fn register(&self, user_name: &str, password: &Zeroizing<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut block = ControlBlock::default();
+        login::register(&self.ctx, &mut block, user_name.to_string(), password).await?;
+        self.set_session(user_name, block);
+        Ok(())
+    }
+
+    fn set_session(&self, user_name: &str, block: ControlBlock) {
+        let mut state = self.state.write().unwrap();
+        state.user_name = Some(user_name.to_string());
+        state.block = block;
+    }
+
+    /// Forgets the session, including the saved one.
+    pub async fn logout(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut block = self.block();
+        *self.state.write().unwrap() = State::default();
+        login::logout(&self.ctx, &mut block).await
+    }
+
+    /// Refreshes the token when it gets close to expiring and saves the new one.
+    pub async fn refresh(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let user_name = match self.user_name() {
+            Some(user_name) => user_name,
+            None => return Ok(()),
+        };
+
+        let mut block = self.block();
+        let jwt = self.state.read().unwrap().block.jwt.clone();
+        authorization::refresh(&self.ctx, &mut block).await?;
+        if block.jwt != jwt && let Err(e) = session::save(&self.ctx, &user_name, &block).await {
+            tracing::warn!("save session failed: {:?}", e);
+        }
+        self.state.write().unwrap().block = block;
+        Ok(())
+    }
+
+    /// The endpoint and address this client last connected to.
+    pub fn server(&self) -> Option<(Endpoint, SocketAddr)> {
+        self.ctx.last_used()
+    }
+
+    /// Keeps the token fresh in the background until the guard is dropped, e.g. while waiting
+    /// for files to upload. Transfers do this on their own.
+    pub fn keep_alive(&self) -> KeepAlive {
+        authorization::keep_alive(&self.ctx, &self.block())
+    }
+
+    /// Lists the files whose name contains `filter`, all files if it is empty.
+    pub async fn list(&self, filter: &str) -> Result<Vec<FileInfo>, Box<dyn std::error::Error>> {
+        Ok(info::list_file(&self.ctx, filter.to_string()).await?.file_info)
+    }
+
+    /// Finds the files named `spec` or matching it as a glob, newest first.
+    pub async fn find(&self, spec: &str) -> Result<Vec<FileInfo>, Box<dyn std::error::Error>> {
+        info::find_files(&self.ctx, spec).await
+    }
+
+    pub async fn info(&self, file_id: FileId) -> Result<FileInfo, Box<dyn std::error::Error>> {
+        biz::get_file_info(&self.ctx, file_id).await
+    }
+
+    /// The file info with every block fetched and checked.
+    pub async fn details(&self, file_id: FileId) -> Result<FileDetails, Box<dyn std::error::Error>> {
+        info::file_details(&self.ctx, self.block(), file_id).await
+    }
+
+    /// Uploads the local file at `local_path` as `file_name`, returning the new file id.
+    pub async fn upload(&self, local_path: &str, file_name: &str, transfer: &Transfer) -> Result<FileId, Box<dyn std::error::Error>> {
+        upload::upload_as(&self.ctx, self.block(), local_path, file_name, transfer.clone()).await
+    }
+
+    /// Uploads everything read from `reader` as `file_name`, returning the new file id.
+    pub async fn upload_stream<R>(&self, reader: &mut R, file_name: &str, transfer: &Transfer) -> Result<FileId, Box<dyn std::error::Error>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        upload::upload_stream(&self.ctx, self.block(), reader, file_name, transfer.clone()).await
+    }
+
+    /// Downloads `file_id` into the directory `target_path`, as `file_name` if given.
+    /// Returns the written path, or `None` if `policy` skipped an existing file.
+    pub async fn download(
+        &self,
+        file_id: FileId,
+        target_path: &str,
+        file_name: Option<&str>,
+        policy: OverwritePolicy,
+        transfer: &Transfer,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        download::download_to(&self.ctx, self.block(), file_id, target_path, file_name, policy, transfer.clone()).await
+    }
+
+    /// Writes `file_id` to `writer` in order; the checksum is verified at the end.
+    pub async fn download_stream<W>(&self, file_id: FileId, writer: &mut W, transfer: &Transfer) -> Result<(), Box<dyn std::error::Error>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        download::download_stream(&self.ctx, self.block(), file_id, writer, transfer.clone()).await
+    }
+
+    pub async fn delete(&self, file_id: FileId) -> Result<(), Box<dyn std::error::Error>> {
+        info::delete_file(&self.ctx, self.block(), file_id).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(profile: &str, state_dir: &str) -> ClientConfig {
+        ClientConfig { profile: profile.to_string(), state_dir: state_dir.to_string(), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_clients_are_independent() {
+        let state_dir = std::env::temp_dir().join(format!("client_api_test_{}", uuid::Uuid::new_v4()));
+        let state_dir = state_dir.to_string_lossy();
+        let work = Client::new(config("work", &state_dir));
+        let home = Client::new(config("home", &state_dir));
+        assert_eq!(work.config().profile, "work");
+        assert_eq!(home.config().profile, "home");
+
+        work.set_session("alice", ControlBlock { jwt: "work.1".to_string(), exp: 100 });
+        // what a transfer does after refreshing the token
+        authorization::share(&work.ctx, &ControlBlock { jwt: "work.2".to_string(), exp: 200 });
+        assert_eq!(work.block().jwt, "work.2");
+        assert_eq!(home.user_name(), None);
+        assert_eq!(home.block().jwt, "");
+
+        drop(home);
+        assert_eq!(work.user_name().as_deref(), Some("alice"));
+        assert_eq!(work.block().jwt, "work.2");
+
+        work.logout().await.unwrap();
+        assert_eq!(work.user_name(), None);
+        assert_eq!(work.block().jwt, "");
+    }
+}
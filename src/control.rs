@@ -1,7 +1,86 @@
+use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ControlBlock {
     pub jwt: String,
     pub exp: i64
-}
\ No newline at end of file
+}
+
+/// The registered claims of a token; anything else the server puts in is ignored.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(from = "RawClaims")]
+pub struct Claims {
+    /// The user, from `sub`, `user_name` or `username`, whichever comes first in that order.
+    pub sub: Option<String>,
+    pub iss: Option<String>,
+    pub iat: Option<i64>,
+    pub exp: Option<i64>,
+}
+
+/// Claims as sent. Servers name the user differently and some send more than one name.
+#[derive(Deserialize)]
+struct RawClaims {
+    sub: Option<String>,
+    user_name: Option<String>,
+    username: Option<String>,
+    iss: Option<String>,
+    iat: Option<i64>,
+    exp: Option<i64>,
+}
+
+impl From<RawClaims> for Claims {
+    fn from(raw: RawClaims) -> Self {
+        Claims {
+            sub: raw.sub.or(raw.user_name).or(raw.username),
+            iss: raw.iss,
+            iat: raw.iat,
+            exp: raw.exp,
+        }
+    }
+}
+
+impl ControlBlock {
+    /// Decodes the token payload. The signature is not checked, that is the server's job.
+    pub fn claims(&self) -> Result<Claims, Box<dyn std::error::Error>> {
+        let payload = match self.jwt.split('.').collect::<Vec<_>>()[..] {
+            [_, payload, _] => payload,
+            _ => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, "token is not a jwt"))),
+        };
+
+        // jwt uses unpadded base64url, but tolerate padding
+        let payload = general_purpose::URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))?;
+        Ok(serde_json::from_slice(&payload)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_claims() {
+        let payload = general_purpose::URL_SAFE_NO_PAD.encode(r#"{"user_name":"alice","iss":"file-server","iat":1700000000,"exp":1700086400,"role":1}"#);
+        let block = ControlBlock {
+            jwt: format!("eyJhbGciOiJIUzI1NiJ9.{}.c2lnbmF0dXJl", payload),
+            exp: 1700086400,
+        };
+
+        let claims = block.claims().unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.iss.as_deref(), Some("file-server"));
+        assert_eq!(claims.iat, Some(1700000000));
+        assert_eq!(claims.exp, Some(1700086400));
+
+        assert!(ControlBlock::default().claims().is_err());
+    }
+
+    #[test]
+    fn test_claims_user_name() {
+        let claims = |json: &str| serde_json::from_str::<Claims>(json).unwrap().sub;
+        assert_eq!(claims(r#"{"sub":"alice","user_name":"bob","username":"carol"}"#).as_deref(), Some("alice"));
+        assert_eq!(claims(r#"{"username":"carol","user_name":"bob"}"#).as_deref(), Some("bob"));
+        assert_eq!(claims(r#"{"sub":null,"username":"carol"}"#).as_deref(), Some("carol"));
+        assert_eq!(claims(r#"{"iss":"file-server"}"#), None);
+    }
+}
@@ -1,18 +1,22 @@
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize as _;
 
-use crate::{control::ControlBlock, core::req::{req_server, Payload, Resp}};
+use crate::{
+    control::ControlBlock,
+    core::{client::Context, req::{req_server, Payload, Resp}, types::{BlockHandle, BlockId, ByteSize, FileId}},
+};
 
 #[derive(Serialize, Debug)]
 struct PresendReq {
     pub file_name: String,
-    pub file_size: u64,
+    pub file_size: ByteSize,
 }
 
-pub async fn presend(block: ControlBlock, file_name: &str, file_size: usize) -> Result<u32, Box<dyn std::error::Error>> {
+pub async fn presend(ctx: &Context, block: ControlBlock, file_name: &str, file_size: ByteSize) -> Result<FileId, Box<dyn std::error::Error>> {
     let req = PresendReq {
         file_name: file_name.to_string(),
-        file_size: file_size as u64,
+        file_size,
     };
 
     let payload = Payload {
@@ -21,7 +25,7 @@ pub async fn presend(block: ControlBlock, file_name: &str, file_size: usize) ->
         content: Some(req),
     };
 
-    let resp: Resp<u32> = req_server(payload).await?;
+    let resp: Resp<FileId> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "presend failed")));
@@ -37,13 +41,13 @@ pub async fn presend(block: ControlBlock, file_name: &str, file_size: usize) ->
 
 #[derive(Serialize, Debug)]
 struct SendReq {
-    pub file_id: u32,
-    pub block_id: u64,
+    pub file_id: FileId,
+    pub block_id: BlockId,
     pub block_checksum: u32,
     pub block_payload: Vec<u8>,
 }
 
-pub async fn send(block: ControlBlock, file_id: u32, block_id: u64, block_checksum: u32, block_payload: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn send(ctx: &Context, block: ControlBlock, file_id: FileId, block_id: BlockId, block_checksum: u32, block_payload: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
     let req = SendReq {
         file_id,
         block_id,
@@ -57,7 +61,7 @@ pub async fn send(block: ControlBlock, file_id: u32, block_id: u64, block_checks
         content: Some(req),
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "send failed")));
@@ -68,11 +72,11 @@ pub async fn send(block: ControlBlock, file_id: u32, block_id: u64, block_checks
 
 #[derive(Serialize, Debug)]
 struct FinishReq {
-    pub file_id: u32,
+    pub file_id: FileId,
     pub file_checksum: u32,
 }
 
-pub async fn finish(block: ControlBlock, file_id: u32, file_checksum: u32) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn finish(ctx: &Context, block: ControlBlock, file_id: FileId, file_checksum: u32) -> Result<(), Box<dyn std::error::Error>> {
     let req = FinishReq {
         file_id,
         file_checksum,
@@ -84,7 +88,7 @@ pub async fn finish(block: ControlBlock, file_id: u32, file_checksum: u32) -> Re
         content: Some(req),
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "finish failed")));
@@ -95,15 +99,15 @@ pub async fn finish(block: ControlBlock, file_id: u32, file_checksum: u32) -> Re
 
 #[derive(Serialize, Debug)]
 pub struct GetBlockIdsByFileIdReq {
-    file_id: i32,
+    file_id: FileId,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct GetBlockIdsByFileIdResp {
-    pub block_ids: Vec<i32>,
+    pub block_ids: Vec<BlockHandle>,
 }
 
-pub async fn get_block_ids(block: ControlBlock, file_id: i32) -> Result<GetBlockIdsByFileIdResp, Box<dyn std::error::Error>> {
+pub async fn get_block_ids(ctx: &Context, block: ControlBlock, file_id: FileId) -> Result<GetBlockIdsByFileIdResp, Box<dyn std::error::Error>> {
     let req = GetBlockIdsByFileIdReq {
         file_id,
     };
@@ -114,7 +118,7 @@ pub async fn get_block_ids(block: ControlBlock, file_id: i32) -> Result<GetBlock
         content: Some(req),
     };
 
-    let resp: Resp<GetBlockIdsByFileIdResp> = req_server(payload).await?;
+    let resp: Resp<GetBlockIdsByFileIdResp> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "get_block_ids failed")));
@@ -128,7 +132,7 @@ pub async fn get_block_ids(block: ControlBlock, file_id: i32) -> Result<GetBlock
 
 #[derive(Serialize, Debug)]
 pub struct GetBlockReq {
-    block_id: i32,
+    block_id: BlockHandle,
 }
 
 #[derive(Deserialize, Debug)]
@@ -139,16 +143,16 @@ pub struct GetBlockResp {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileBlock {
-    pub id: i32,
-    pub file_id: i32,
+    pub id: BlockHandle,
+    pub file_id: FileId,
     pub block_name: String,
-    pub block_id: i64,
+    pub block_id: BlockId,
     pub block_checksum: u32,
-    pub block_size: u32,
+    pub block_size: ByteSize,
     pub created_at: NaiveDateTime,
 }
 
-pub async fn get_block(block: ControlBlock, block_id: i32) -> Option<GetBlockResp> {
+pub async fn get_block(ctx: &Context, block: ControlBlock, block_id: BlockHandle) -> Option<GetBlockResp> {
     let req = GetBlockReq {
         block_id,
     };
@@ -159,7 +163,7 @@ pub async fn get_block(block: ControlBlock, block_id: i32) -> Option<GetBlockRes
         content: Some(req),
     };
 
-    let resp: Resp<GetBlockResp> = match req_server(payload).await {
+    let resp: Resp<GetBlockResp> = match req_server(ctx, payload).await {
         Ok(resp) => resp,
         Err(_) => return None,
     };
@@ -183,15 +187,15 @@ pub struct ListFileResp {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileInfo {
-    pub id: i32,
+    pub id: FileId,
     pub file_name: String,
-    pub file_size: i64,
+    pub file_size: ByteSize,
     pub file_checksum: u32,
     pub file_status: i32,
     pub created_at: NaiveDateTime,
 }
 
-pub async fn list_file(filter: String) -> Result<ListFileResp, Box<dyn std::error::Error>> {
+pub async fn list_file(ctx: &Context, filter: String) -> Result<ListFileResp, Box<dyn std::error::Error>> {
     let req = ListFileReq {
         filter,
     };
@@ -202,7 +206,7 @@ pub async fn list_file(filter: String) -> Result<ListFileResp, Box<dyn std::erro
         content: Some(req),
     };
 
-    let resp: Resp<ListFileResp> = req_server(payload).await?;
+    let resp: Resp<ListFileResp> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "list_file failed")));
@@ -216,10 +220,10 @@ pub async fn list_file(filter: String) -> Result<ListFileResp, Box<dyn std::erro
 
 #[derive(Serialize, Debug)]
 pub struct DeleteFileReq {
-    file_id: i32,
+    file_id: FileId,
 }
 
-pub async fn delete_file(block: ControlBlock, file_id: i32) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn delete_file(ctx: &Context, block: ControlBlock, file_id: FileId) -> Result<(), Box<dyn std::error::Error>> {
     let req = DeleteFileReq {
         file_id,
     };
@@ -230,7 +234,7 @@ pub async fn delete_file(block: ControlBlock, file_id: i32) -> Result<(), Box<dy
         content: Some(req),
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "delete_file failed")));
@@ -240,14 +244,14 @@ pub async fn delete_file(block: ControlBlock, file_id: i32) -> Result<(), Box<dy
 }
 
 #[allow(unused)]
-pub async fn ping() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn ping(ctx: &Context) -> Result<(), Box<dyn std::error::Error>> {
     let payload: Payload<u32> = Payload {
         method: "ping".to_string(),
         block: None,
         content: None,
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "ping failed")));
@@ -262,7 +266,13 @@ pub struct RegisterReq {
     pub password: String,
 }
 
-pub async fn register(block: &mut ControlBlock, user_name: String, password: String) -> Result<(), Box<dyn std::error::Error>> {
+impl Drop for RegisterReq {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+pub async fn register(ctx: &Context, block: &mut ControlBlock, user_name: String, password: String) -> Result<(), Box<dyn std::error::Error>> {
     let req = RegisterReq {
         user_name,
         password,
@@ -276,7 +286,7 @@ pub async fn register(block: &mut ControlBlock, user_name: String, password: Str
         content: Some(req),
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "register failed")));
@@ -296,7 +306,13 @@ pub struct LoginReq {
     pub password: String,
 }
 
-pub async fn login(block: &mut ControlBlock, user_name: String, password: String) -> Result<(), Box<dyn std::error::Error>> {
+impl Drop for LoginReq {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+pub async fn login(ctx: &Context, block: &mut ControlBlock, user_name: String, password: String) -> Result<(), Box<dyn std::error::Error>> {
     let req = LoginReq {
         user_name,
         password,
@@ -310,7 +326,7 @@ pub async fn login(block: &mut ControlBlock, user_name: String, password: String
         content: Some(req),
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
     if !resp.success {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "login failed")));
@@ -324,17 +340,18 @@ pub async fn login(block: &mut ControlBlock, user_name: String, password: String
     Ok(())
 }
 
-pub async fn refresh(block: &mut ControlBlock) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn refresh(ctx: &Context, block: &mut ControlBlock) -> Result<(), Box<dyn std::error::Error>> {
     let payload: Payload<u32> = Payload {
         method: "refresh".to_string(),
         block: Some(block.clone()),
         content: None,
     };
 
-    let resp: Resp<()> = req_server(payload).await?;
+    let resp: Resp<()> = req_server(ctx, payload).await?;
 
+    // the server answered and turned the token down, as opposed to not being reachable
     if !resp.success {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "refresh failed")));
+        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "refresh rejected")));
     }
 
     *block = match resp.block {
@@ -347,10 +364,10 @@ pub async fn refresh(block: &mut ControlBlock) -> Result<(), Box<dyn std::error:
 
 #[derive(Serialize, Debug)]
 pub struct GetFileInfoReq {
-    file_id: i32,
+    file_id: FileId,
 }
 
-pub async fn get_file_info(file_id: i32) -> Result<FileInfo, Box<dyn std::error::Error>> {
+pub async fn get_file_info(ctx: &Context, file_id: FileId) -> Result<FileInfo, Box<dyn std::error::Error>> {
     let req = GetFileInfoReq {
         file_id: file_id
     };
@@ -361,14 +378,41 @@ pub async fn get_file_info(file_id: i32) -> Result<FileInfo, Box<dyn std::error:
         content: Some(req),
     };
 
-    let resp: Resp<FileInfo> = req_server(payload).await?;
+    let resp: Resp<FileInfo> = req_server(ctx, payload).await?;
 
+    // the server answers but has no such file
     if !resp.success {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "get_file_info failed")));
+        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "get_file_info failed")));
     }
 
     match resp.content {
         Some(file_info) => Ok(file_info),
         None => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "get_file_info failed"))),
     }
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetQuotaResp {
+    pub used: ByteSize,
+    pub limit: ByteSize,
+}
+
+/// Asks for the user's storage quota. Not every server has this call, see `ClientConfig::check_quota`.
+pub async fn get_quota(ctx: &Context, block: ControlBlock) -> Result<GetQuotaResp, Box<dyn std::error::Error>> {
+    let payload: Payload<u32> = Payload {
+        method: "get_quota".to_string(),
+        block: Some(block),
+        content: None,
+    };
+
+    let resp: Resp<GetQuotaResp> = req_server(ctx, payload).await?;
+
+    if !resp.success {
+        return Err(Box::new(std::io::Error::other("get_quota failed")));
+    }
+
+    match resp.content {
+        Some(quota) => Ok(quota),
+        None => Err(Box::new(std::io::Error::other("get_quota failed"))),
+    }
+}
@@ -1,20 +1,105 @@
-use tokio::sync::OnceCell;
+use std::{net::SocketAddr, sync::{Arc, Mutex}};
 
-#[derive(Debug)]
+use openssl::ssl::SslVersion;
+
+use crate::{
+    core::{endpoint::Endpoint, pool::Pool, proxy::ProxyConfig, tls::ClientIdentity},
+    file::{download::OverwritePolicy, info::Disambiguation},
+    user::authorization::Tokens,
+};
+
+#[derive(Debug, Default)]
 pub struct ClientConfig {
+    /// CA file trusted for the server certificate, empty for none.
     pub cert_file: String,
-    pub addr: String,
-    pub port: u16,
-    pub domain: String,
-    pub debug: bool,
+    /// Also trust the system's CA store.
+    pub system_trust: bool,
+    /// Directory of extra PEM CA bundles to trust.
+    pub ca_dir: Option<String>,
+    /// Accepted server keys as `sha256/<base64>` SPKI fingerprints; any key if empty.
+    pub pins: Vec<String>,
+    /// Record the server key on first connect per profile and refuse a changed one.
+    pub trust_on_first_use: bool,
+    /// Servers tried in order until one answers.
+    pub endpoints: Vec<Endpoint>,
+    /// Name to send as SNI and verify the certificate against, the endpoint host if none.
+    pub domain: Option<String>,
+    /// Proxy the endpoints are reached through, with the hosts that bypass it.
+    pub proxies: ProxyConfig,
+    /// Oldest and newest TLS versions offered, openssl's defaults if none.
+    pub tls_min_version: Option<SslVersion>,
+    pub tls_max_version: Option<SslVersion>,
+    /// Cipher list for TLS 1.2 and below, in openssl syntax.
+    pub cipher_list: Option<String>,
+    /// Ciphersuites for TLS 1.3, in openssl syntax.
+    pub ciphersuites: Option<String>,
+    /// CRLs to check the server chain against; enables revocation checking when not empty.
+    pub crl_files: Vec<String>,
+    /// Client certificate for servers that authenticate clients by certificate as well as by token.
+    pub client_identity: Option<ClientIdentity>,
+    /// Trace logs show passwords, tokens and block data as sent, for protocol troubleshooting.
+    pub debug_unsafe: bool,
+    pub disambiguation: Disambiguation,
+    /// Server accepts `presend` with a zero size for streams of unknown length.
+    pub deferred_presend: bool,
+    /// Server has the `get_quota` call, so uploads are checked against the quota before they start.
+    pub check_quota: bool,
+    pub overwrite: OverwritePolicy,
+    /// Where the client keeps its own files, e.g. transfer journals.
+    pub state_dir: String,
+    /// Name of the saved login session, so several accounts can stay logged in.
+    pub profile: String,
+    /// Encrypts saved sessions with the key in this file, created on first use.
+    pub session_key_file: Option<String>,
 }
 
-static CONFIG: OnceCell<ClientConfig> = OnceCell::const_new();
+/// `$XDG_STATE_HOME/rust_ssl_file_client`, falling back to `~/.local/state`.
+pub fn default_state_dir() -> String {
+    match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => format!("{}/rust_ssl_file_client", dir),
+        _ => match std::env::var("HOME") {
+            Ok(home) => format!("{}/.local/state/rust_ssl_file_client", home),
+            Err(_) => ".client_state".to_string(),
+        },
+    }
+}
+
+/// What the requests of one `Client` share: its config, its pooled connections, its newest token
+/// and the server it last connected to. Clones are cheap and share all of it, so block tasks take their own.
+#[derive(Debug, Clone)]
+pub struct Context(Arc<Shared>);
 
-pub async fn init_config(config: ClientConfig) {
-    CONFIG.set(config).unwrap();
+#[derive(Debug)]
+struct Shared {
+    config: ClientConfig,
+    pool: Pool,
+    tokens: Tokens,
+    last_used: Mutex<Option<(Endpoint, SocketAddr)>>,
 }
 
-pub async fn get_config() -> &'static ClientConfig {
-    CONFIG.get().unwrap()
+impl Context {
+    pub fn new(config: ClientConfig) -> Self {
+        Context(Arc::new(Shared { config, pool: Pool::new(), tokens: Tokens::default(), last_used: Mutex::new(None) }))
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.0.config
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.0.pool
+    }
+
+    pub fn tokens(&self) -> &Tokens {
+        &self.0.tokens
+    }
+
+    /// The endpoint and address of the last successful connection.
+    pub fn last_used(&self) -> Option<(Endpoint, SocketAddr)> {
+        self.0.last_used.lock().unwrap().clone()
+    }
+
+    pub fn set_last_used(&self, endpoint: Endpoint, addr: SocketAddr) {
+        *self.0.last_used.lock().unwrap() = Some((endpoint, addr));
+    }
 }
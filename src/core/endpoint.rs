@@ -0,0 +1,234 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs as _},
+    str::FromStr,
+    sync::mpsc,
+    time::Duration,
+};
+
+use crate::core::proxy::{self, ProxyConfig};
+
+pub const DEFAULT_PORT: u16 = 17878;
+/// Head start each address gets before the next one is tried in parallel.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server to dial, written `host`, `host:port`, `1.2.3.4:port`, `::1` or `[::1]:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |port: &str| port.parse::<u16>().map_err(|_| format!("illegal port in endpoint {}", s));
+
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(|| format!("missing ] in endpoint {}", s))?;
+            let port = match rest {
+                "" => DEFAULT_PORT,
+                _ => parse_port(rest.strip_prefix(':').ok_or_else(|| format!("illegal endpoint {}", s))?)?,
+            };
+            (host, port)
+        } else if s.matches(':').count() > 1 {
+            // an unbracketed IPv6 literal can't carry a port
+            (s, DEFAULT_PORT)
+        } else {
+            match s.split_once(':') {
+                Some((host, port)) => (host, parse_port(port)?),
+                None => (s, DEFAULT_PORT),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(format!("missing host in endpoint {}", s));
+        }
+        if host.contains(':') && host.parse::<IpAddr>().is_err() {
+            return Err(format!("illegal IPv6 address in endpoint {}", s));
+        }
+        Ok(Endpoint { host: host.to_string(), port })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl Endpoint {
+    /// The name to send as SNI and to verify the certificate against; IP literals have none.
+    pub fn server_name(&self) -> Option<&str> {
+        match self.host.parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => Some(&self.host),
+        }
+    }
+}
+
+/// Failover list of a profile, one endpoint per line in `{state_dir}/endpoints/{profile}`;
+/// empty if the file doesn't exist. Blank lines and `#` comments are skipped.
+pub fn profile_endpoints(state_dir: &str, profile: &str) -> Result<Vec<Endpoint>, String> {
+    let path = format!("{}/endpoints/{}", state_dir, profile);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("read {} failed: {}", path, e)),
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse().map_err(|e| format!("{} in {}", e, path)))
+        .collect()
+}
+
+/// Alternates address families, starting with the first one the resolver returned (RFC 8305).
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    first.reverse();
+    second.reverse();
+
+    let mut sorted = Vec::new();
+    while !first.is_empty() || !second.is_empty() {
+        sorted.extend(first.pop());
+        sorted.extend(second.pop());
+    }
+    sorted
+}
+
+/// Connects to whichever address answers first, starting a new attempt every `ATTEMPT_DELAY`
+/// or as soon as the previous one fails.
+fn race(addrs: &[SocketAddr]) -> std::io::Result<(SocketAddr, TcpStream)> {
+    let (tx, rx) = mpsc::channel();
+    let mut pending = 0;
+    let mut last_err = None;
+
+    let handle = |rst: (SocketAddr, std::io::Result<TcpStream>), last_err: &mut Option<std::io::Error>| match rst {
+        (addr, Ok(stream)) => Some((addr, stream)),
+        (addr, Err(e)) => {
+            tracing::debug!("connect to {} failed: {}", addr, e);
+            *last_err = Some(e);
+            None
+        },
+    };
+
+    for addr in addrs.iter().copied() {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            // the receiver is gone once another address won, the stream is dropped then
+            let _ = tx.send((addr, TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)));
+        });
+        pending += 1;
+
+        if let Ok(rst) = rx.recv_timeout(ATTEMPT_DELAY) {
+            pending -= 1;
+            if let Some(won) = handle(rst, &mut last_err) {
+                return Ok(won);
+            }
+        }
+    }
+
+    while pending > 0 {
+        let rst = match rx.recv() {
+            Ok(rst) => rst,
+            Err(_) => break,
+        };
+        pending -= 1;
+        if let Some(won) = handle(rst, &mut last_err) {
+            return Ok(won);
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// Resolves the endpoint and connects to whichever of its addresses answers first.
+pub fn connect(endpoint: &Endpoint) -> std::io::Result<(SocketAddr, TcpStream)> {
+    let addrs = (endpoint.host.as_str(), endpoint.port).to_socket_addrs()?;
+    race(&interleave(addrs.collect()))
+}
+
+/// Dials the endpoints in order, through the proxy unless excluded, falling back to the next one
+/// when every address of an endpoint fails. Returns the endpoint and address that answered.
+pub fn dial(endpoints: &[Endpoint], proxies: &ProxyConfig) -> Result<(Endpoint, SocketAddr, TcpStream), Box<dyn std::error::Error>> {
+    let mut errors = Vec::new();
+
+    for endpoint in endpoints {
+        let rst = match proxies.proxy_for(endpoint) {
+            Some(proxy) => proxy::tunnel(proxy, endpoint),
+            None => connect(endpoint),
+        };
+
+        match rst {
+            Ok((addr, stream)) => {
+                if endpoint != &endpoints[0] {
+                    tracing::warn!("{} is unreachable, using failover {}", endpoints[0], endpoint);
+                }
+                tracing::debug!("connected to {} at {}", endpoint, addr);
+                return Ok((endpoint.clone(), addr, stream));
+            },
+            Err(e) => {
+                tracing::warn!("connect to {} failed: {}", endpoint, e);
+                errors.push(format!("{}: {}", endpoint, e));
+            },
+        }
+    }
+
+    Err(Box::new(std::io::Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        format!("no server reachable ({})", errors.join("; ")),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint = |host: &str, port| Endpoint { host: host.to_string(), port };
+        assert_eq!("files.example.com".parse(), Ok(endpoint("files.example.com", DEFAULT_PORT)));
+        assert_eq!("10.0.0.1:443".parse(), Ok(endpoint("10.0.0.1", 443)));
+        assert_eq!("[::1]:443".parse(), Ok(endpoint("::1", 443)));
+        assert_eq!("[fe80::1]".parse(), Ok(endpoint("fe80::1", DEFAULT_PORT)));
+        assert_eq!("::1".parse(), Ok(endpoint("::1", DEFAULT_PORT)));
+        assert!("[::1:443".parse::<Endpoint>().is_err());
+        assert!("host:port".parse::<Endpoint>().is_err());
+        assert!(":443".parse::<Endpoint>().is_err());
+
+        assert_eq!(endpoint("::1", 443).to_string(), "[::1]:443");
+        assert_eq!(endpoint("::1", 443).server_name(), None);
+        assert_eq!(endpoint("files.example.com", 443).server_name(), Some("files.example.com"));
+    }
+
+    #[test]
+    fn test_interleave() {
+        let v4 = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let v6 = |port| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        assert_eq!(interleave(vec![v6(1), v6(2), v4(3), v4(4), v4(5)]), vec![v6(1), v4(3), v6(2), v4(4), v4(5)]);
+        assert_eq!(interleave(vec![v4(1), v6(2)]), vec![v4(1), v6(2)]);
+    }
+
+    #[test]
+    fn test_dial_failover() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = Endpoint { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port() };
+        // bind and drop to get a port nobody listens on
+        let dead_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let dead = Endpoint { host: "127.0.0.1".to_string(), port: dead_port };
+
+        let (used, _addr, _stream) = dial(&[dead.clone(), live.clone()], &ProxyConfig::default()).unwrap();
+        assert_eq!(used, live);
+        assert!(dial(&[dead], &ProxyConfig::default()).is_err());
+    }
+}
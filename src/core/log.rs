@@ -0,0 +1,40 @@
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{fmt, layer::SubscriberExt as _, util::SubscriberInitExt as _, EnvFilter, Layer as _};
+
+/// Filter used when neither `--log` nor `$CLIENT_LOG` is given.
+pub const DEFAULT_LOG_FILTER: &str = "warn";
+/// Environment variable with a filter like `info,client::file=debug`.
+pub const LOG_ENV: &str = "CLIENT_LOG";
+/// Daily log files kept in the log directory.
+const MAX_LOG_FILES: usize = 7;
+
+/// Sends log records matching `filter` to stderr and, when `log_dir` is given,
+/// to daily rotated `client.*.log` files there.
+pub fn init_logging(filter: &str, log_dir: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let console = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .with_filter(EnvFilter::try_new(filter)?);
+
+    // written synchronously, the client exits with `exit` and a background writer would lose the tail
+    let file = match log_dir {
+        Some(log_dir) => {
+            std::fs::create_dir_all(log_dir)?;
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix("client")
+                .filename_suffix("log")
+                .max_log_files(MAX_LOG_FILES)
+                .build(log_dir)?;
+            let layer = fmt::layer()
+                .with_writer(appender)
+                .with_ansi(false)
+                .with_filter(EnvFilter::try_new(filter)?);
+            Some(layer)
+        },
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(console).with(file).try_init()?;
+    Ok(())
+}
@@ -1,6 +1,13 @@
 pub mod req;
 pub mod client;
 pub mod biz;
+pub mod endpoint;
+pub mod log;
+pub mod pool;
+pub mod proxy;
+pub mod redact;
+pub mod tls;
+pub mod types;
 
 #[allow(unused)]
 pub const MAX_BLOCK_SIZE: usize = 16 * MB;
@@ -0,0 +1,76 @@
+use std::{
+    net::TcpStream,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use openssl::ssl::SslStream;
+
+/// Idle connections kept for reuse.
+const MAX_IDLE: usize = 8;
+/// Idle connections older than this are dropped rather than risking one the server gave up on.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type Connection = SslStream<TcpStream>;
+
+/// TLS connections the server left open after a response, reused by the next requests
+/// to save the handshake. Servers that close after every response never fill it.
+#[derive(Debug)]
+pub struct Pool {
+    idle: Mutex<Vec<(Instant, Connection)>>,
+}
+
+impl Pool {
+    pub const fn new() -> Self {
+        Pool { idle: Mutex::new(Vec::new()) }
+    }
+
+    /// The most recently used connection that is still open.
+    pub fn take(&self) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some((since, conn)) = idle.pop() {
+            if since.elapsed() < IDLE_TIMEOUT && is_open(&conn) {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    /// Keeps `conn` for later if the server left it open and idle.
+    pub fn put(&self, conn: Connection) {
+        if !is_open(&conn) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|(since, _)| since.elapsed() < IDLE_TIMEOUT);
+        if idle.len() >= MAX_IDLE {
+            idle.remove(0);
+        }
+        idle.push((Instant::now(), conn));
+    }
+
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool::new()
+    }
+}
+
+/// Whether nothing has arrived on `conn` since the last response and the server hasn't closed it.
+/// Anything readable, a close_notify included, makes it unusable for the next request.
+fn is_open(conn: &Connection) -> bool {
+    if conn.ssl().pending() > 0 {
+        return false;
+    }
+    let tcp = conn.get_ref();
+    if tcp.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0; 1];
+    let idle = matches!(tcp.peek(&mut byte), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock);
+    tcp.set_nonblocking(false).is_ok() && idle
+}
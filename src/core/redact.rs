@@ -0,0 +1,71 @@
+use crc_fast::{checksum, CrcAlgorithm::Crc32IsoHdlc};
+use serde_json::Value;
+
+/// Fields whose value is never shown in debug output.
+const SECRET_KEYS: [&str; 3] = ["password", "jwt", "token"];
+/// Fields carrying file data, shown as length and checksum.
+const PAYLOAD_KEYS: [&str; 2] = ["block_payload", "block_data"];
+
+/// Masks secrets and file data in a request or response before it is logged.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                    *value = Value::String(mask_secret(value));
+                } else if PAYLOAD_KEYS.contains(&key.as_str()) {
+                    *value = Value::String(summarize_bytes(value));
+                } else {
+                    redact(value);
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {},
+    }
+}
+
+/// Parses `json` and redacts it; anything that isn't json is only shown by length.
+pub fn redact_json(json: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(json) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        },
+        Err(_) => format!("<{} bytes>", json.len()),
+    }
+}
+
+fn mask_secret(value: &Value) -> String {
+    match value {
+        Value::String(secret) => format!("<redacted, {} chars>", secret.len()),
+        _ => "<redacted>".to_string(),
+    }
+}
+
+fn summarize_bytes(value: &Value) -> String {
+    let bytes = match value {
+        Value::Array(values) => values.iter().filter_map(|byte| byte.as_u64().map(|byte| byte as u8)).collect::<Vec<_>>(),
+        Value::String(data) => data.as_bytes().to_vec(),
+        _ => return "<redacted>".to_string(),
+    };
+    format!("<{} bytes, crc32 {:08x}>", bytes.len(), checksum(Crc32IsoHdlc, &bytes) as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let json = br#"{"jwt":"a.b.c","exp":42,"user_name":"alice","password":"hunter2","block_payload":[1,2,3]}"#;
+        let redacted = redact_json(json);
+
+        assert!(!redacted.contains("a.b.c"));
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("alice"));
+        assert!(redacted.contains("42"));
+        assert!(redacted.contains("<3 bytes, crc32"));
+        assert_eq!(redact_json(b"not json"), "<8 bytes>");
+    }
+}
@@ -1,14 +1,11 @@
-use anstyle::{Color, RgbColor, Style};
 use base64::{engine::general_purpose, Engine as _};
-use openssl::ssl::{SslConnector, SslMethod};
 use serde::{Serialize, de::DeserializeOwned};
-use std::{
-    fmt::Debug, io::{Read as _, Write as _}, net::TcpStream
-};
+use std::fmt::Debug;
+use zeroize::Zeroizing;
 
 use crate::{
     control::ControlBlock,
-    core::client::get_config, terminal::async_print,
+    core::{client::Context, pool::Connection, redact::redact_json, tls},
 };
 
 #[derive(Debug)]
@@ -21,26 +18,54 @@ where
     pub content: Option<T>,
 }
 
-async fn make_req<T>(payload: Payload<T>) -> String
+/// Builds the request line, with room left for the end mark. Login and register carry the password
+/// and most requests a token, so every buffer is sized up front and zeroized on drop: a buffer
+/// that grows would leave copies behind.
+async fn make_req<T>(payload: Payload<T>) -> Zeroizing<String>
 where
     T: Serialize,
 {
-    let content = match payload.content {
-        Some(content) => {
-            let json_str = serde_json::to_string(&content).unwrap();
-            general_purpose::STANDARD.encode(json_str)
-        },
-        None => "".to_string(),
+    let content = match &payload.content {
+        Some(content) => encode_json(content),
+        None => Zeroizing::new(String::new()),
     };
-    let block = match payload.block {
-        Some(block) => {
-            let json_str = serde_json::to_string(&block).unwrap();
-            general_purpose::STANDARD.encode(json_str)
-        },
-        None => ".".to_string(),
+    let block = match &payload.block {
+        Some(block) => encode_json(block),
+        None => Zeroizing::new(".".to_string()),
     };
 
-    format!("{} {} {}", payload.method, block, content)
+    let mut req = Zeroizing::new(String::with_capacity(payload.method.len() + block.len() + content.len() + 2 + END_MARK.len()));
+    req.push_str(&payload.method);
+    req.push(' ');
+    req.push_str(&block);
+    req.push(' ');
+    req.push_str(&content);
+    req
+}
+
+fn encode_json<T: Serialize>(value: &T) -> Zeroizing<String> {
+    let mut len = Counter(0);
+    serde_json::to_writer(&mut len, value).unwrap();
+    let mut json = Zeroizing::new(Vec::with_capacity(len.0));
+    serde_json::to_writer(&mut *json, value).unwrap();
+
+    let mut encoded = Zeroizing::new(String::with_capacity(base64::encoded_len(json.len(), true).unwrap()));
+    general_purpose::STANDARD.encode_string(&*json, &mut encoded);
+    encoded
+}
+
+/// Counts the bytes written to it.
+struct Counter(usize);
+
+impl std::io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -53,59 +78,134 @@ where
     pub content: Option<R>,
 }
 
-pub async fn req_server<T, R>(payload: Payload<T>) -> Result<Resp<R>, Box<dyn std::error::Error>>
+#[tracing::instrument(name = "request", skip_all, fields(method = %payload.method))]
+pub async fn req_server<T, R>(ctx: &Context, payload: Payload<T>) -> Result<Resp<R>, Box<dyn std::error::Error>>
 where T: Serialize + Debug, R:DeserializeOwned + Debug
 {
-    async_debug(format!("raw payload: {:?}", payload)).await;
+    let debug_unsafe = ctx.config().debug_unsafe;
+    if debug_unsafe {
+        tracing::trace!("raw payload: {:?}", payload);
+    } else {
+        tracing::debug!("{}", describe_payload(&payload));
+    }
 
+    let method = payload.method.clone();
     let req = make_req(payload).await;
 
-    async_debug(format!("b64 payload: {}", req)).await;
+    if debug_unsafe {
+        tracing::trace!("b64 payload: {}", *req);
+    }
 
-    let resp = send_req(req).await?;
+    let resp = match send_req(ctx, &method, req).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            tracing::warn!("request failed: {:?}", e);
+            return Err(e);
+        }
+    };
 
-    async_debug(resp.clone()).await;
+    if debug_unsafe {
+        tracing::trace!("b64 response: {}", resp);
+    }
 
-    let resp: Resp<R> = split_resp(resp).await;
+    let resp: Resp<R> = split_resp(resp, debug_unsafe).await;
 
     Ok(resp)
 }
 
+/// The payload as it may be logged: passwords, tokens and block data are masked.
+fn describe_payload<T>(payload: &Payload<T>) -> String
+where
+    T: Serialize,
+{
+    let redacted = |json: serde_json::Result<Vec<u8>>| match json {
+        Ok(json) => redact_json(&json),
+        Err(e) => format!("<{}>", e),
+    };
+    let block = payload.block.as_ref().map_or("none".to_string(), |block| redacted(serde_json::to_vec(block)));
+    let content = payload.content.as_ref().map_or("none".to_string(), |content| redacted(serde_json::to_vec(content)));
+
+    format!("request {} block={} content={}", payload.method, block, content)
+}
+
 const END_MARK: &str = "\n\n\n";
 
-async fn send_req(payload: String) -> Result<String, Box<dyn std::error::Error>> {
-    let client_config = get_config().await;
+/// Methods that only read, so sending one twice does no harm.
+const IDEMPOTENT_METHODS: &[&str] = &["list_file", "get_file_info", "get_block_ids", "get_block", "get_quota", "ping"];
+
+async fn send_req(ctx: &Context, method: &str, mut request: Zeroizing<String>) -> Result<String, Box<dyn std::error::Error>> {
+    let client_config = ctx.config();
+
+    let has_identity = client_config.client_identity.is_some();
+    request.push_str(END_MARK);
+
+    // the server may drop a pooled connection any time. A request it may have acted on before
+    // closing is only sent again if that is harmless, others fail rather than run twice.
+    if let Some(mut conn) = ctx.pool().take() {
+        match write_req(&mut conn, &request) {
+            Err(e) => tracing::debug!("pooled connection failed, reconnecting: {}", e),
+            Ok(_) => match read_resp(&mut conn) {
+                Ok((buffer, complete)) if !buffer.is_empty() => return finish_resp(ctx, conn, buffer, complete),
+                Ok(_) if IDEMPOTENT_METHODS.contains(&method) => tracing::debug!("pooled connection was closed, reconnecting"),
+                Err(e) if IDEMPOTENT_METHODS.contains(&method) => tracing::debug!("pooled connection failed, reconnecting: {}", e),
+                Ok(_) => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "server closed the connection without an answer"))),
+                Err(e) => return Err(Box::new(e)),
+            },
+        }
+    }
 
-    let addr = client_config.addr.clone();
-    let port = client_config.port;
-    let cert_file = client_config.cert_file.clone();
-    let domain = &client_config.domain;
+    let (endpoint, addr, mut conn) = tls::connect(client_config)?;
+    ctx.set_last_used(endpoint, addr);
+    let (buffer, complete) = exchange(&mut conn, &request).map_err(|e| tls::explain_error(Box::new(e), has_identity))?;
+    finish_resp(ctx, conn, buffer, complete)
+}
 
-    let mut builder = SslConnector::builder(SslMethod::tls())?;
-    builder.set_ca_file(cert_file)?;
-    let connector = builder.build();
+/// Writes the request and reads the response, see `read_resp`.
+fn exchange(conn: &mut Connection, request: &str) -> std::io::Result<(Vec<u8>, bool)> {
+    write_req(conn, request)?;
+    read_resp(conn)
+}
 
-    let stream = TcpStream::connect(format!("{}:{}", addr, port))?;
-    let mut ssl_stream = connector.connect(domain, stream)?;
+fn write_req(conn: &mut impl std::io::Write, request: &str) -> std::io::Result<()> {
+    conn.write_all(request.as_bytes()).and_then(|_| conn.flush()).map_err(timed_out)
+}
 
-    ssl_stream.write_all(format!("{}{}", payload, END_MARK).as_bytes())?;
-    ssl_stream.flush()?;
+/// A socket timeout shows up as `WouldBlock`; it is reported as `TimedOut`, which callers retry
+/// like any other failed request.
+fn timed_out(e: std::io::Error) -> std::io::Error {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("no progress from the server in {}s", tls::IO_TIMEOUT.as_secs()),
+        ),
+        _ => e,
+    }
+}
 
+/// Reads until the end mark or until the server closes the connection.
+/// Returns the response without the end mark and whether it was complete.
+fn read_resp(conn: &mut impl std::io::Read) -> std::io::Result<(Vec<u8>, bool)> {
     let mut buffer = Vec::new();
     let mut temp_buffer = [0; 1024];
 
     loop {
-        let n = ssl_stream.read(&mut temp_buffer)?;
+        let n = conn.read(&mut temp_buffer).map_err(timed_out)?;
         if n == 0 {
-            break;
+            return Ok((buffer, false));
         }
         buffer.extend_from_slice(&temp_buffer[0..n]);
 
         if buffer.ends_with(END_MARK.as_bytes()) {
             buffer.truncate(buffer.len() - END_MARK.len());
-            break;
+            return Ok((buffer, true));
         }
     }
+}
+
+fn finish_resp(ctx: &Context, conn: Connection, buffer: Vec<u8>, complete: bool) -> Result<String, Box<dyn std::error::Error>> {
+    if complete {
+        ctx.pool().put(conn);
+    }
 
     let response = String::from_utf8(buffer)?;
     let response = response.trim();
@@ -113,13 +213,10 @@ async fn send_req(payload: String) -> Result<String, Box<dyn std::error::Error>>
     Ok(response.to_owned())
 }
 
-async fn split_resp<T>(resp: String) -> Resp<T>
+async fn split_resp<T>(resp: String, debug_unsafe: bool) -> Resp<T>
 where
     T: DeserializeOwned + Debug,
 {
-    let bytes = resp.bytes();
-    async_debug(format!("{:?}", bytes)).await;
-
     let mut parts = resp.split(" ");
 
     let success = match parts.next().unwrap().to_string().as_str() {
@@ -128,27 +225,24 @@ where
         _ => false,
     };
 
-    let block = match parts.next() {
-        Some(str) => {
-            match general_purpose::STANDARD.decode(str) {
-                Ok(block_str) => serde_json::from_slice(&block_str).unwrap_or(None),
-                Err(_) => None,
-            }
-        },
+    let block_str = parts.next().and_then(|str| general_purpose::STANDARD.decode(str).ok());
+    let block = match &block_str {
+        Some(block_str) => serde_json::from_slice(block_str).unwrap_or(None),
         None => None,
     };
 
-    let content = match parts.next() {
-        Some(str) => {
-            match general_purpose::STANDARD.decode(str) {
-                Ok(content_str) => serde_json::from_slice(&content_str).unwrap_or(None),
-                Err(_) => None,
-            }
-        },
+    let content_str = parts.next().and_then(|str| general_purpose::STANDARD.decode(str).ok());
+    let content = match &content_str {
+        Some(content_str) => serde_json::from_slice(content_str).unwrap_or(None),
         None => None,
     };
 
-    async_debug(format!("{} {:?} {:?}", success, block, content)).await;
+    if debug_unsafe {
+        tracing::trace!("response {} {:?} {:?}", success, block, content);
+    } else {
+        let redacted = |json: &Option<Vec<u8>>| json.as_deref().map_or("none".to_string(), redact_json);
+        tracing::debug!("response {} block={} content={}", success, redacted(&block_str), redacted(&content_str));
+    }
 
     Resp {
         success,
@@ -163,24 +257,45 @@ mod test {
     async fn test_split() {
         use super::*;
         let resp = "true . Mw==".to_string();
-        let resp: Resp<u32> = split_resp(resp).await;
+        let resp: Resp<u32> = split_resp(resp, false).await;
         assert_eq!(resp.success, true);
         assert_eq!(resp.content, Some(3));
         assert!(resp.block.is_none());
         println!("{:?}", resp)
     }
-}
-
-pub async fn async_debug(buffer: String) {
 
-    let client_config = get_config().await;
-
-    if !client_config.debug {
-        return;
+    #[tokio::test]
+    async fn test_make_req() {
+        use super::*;
+        let payload = Payload {
+            method: "login".to_string(),
+            block: None,
+            content: Some(serde_json::json!({"user_name": "u", "password": "p"})),
+        };
+        let mut req = make_req(payload).await;
+        // the end mark fits without moving the buffer
+        let capacity = req.capacity();
+        req.push_str(END_MARK);
+        assert_eq!(req.capacity(), capacity);
+        let content = general_purpose::STANDARD.encode(r#"{"user_name":"u","password":"p"}"#);
+        assert_eq!(*req, format!("login . {}{}", content, END_MARK));
     }
 
-    let style = Style::new().bold().fg_color(Some(Color::Rgb(RgbColor(128, 196, 0))));
-
-    let buffer = format!("{style}[DEBUG]{style:#} {}", buffer);
-    async_print(buffer).await
-}
\ No newline at end of file
+    #[test]
+    fn test_read_resp() {
+        use super::*;
+        let (buffer, complete) = read_resp(&mut std::io::Cursor::new("true . Mw==\n\n\n")).unwrap();
+        assert_eq!(buffer, b"true . Mw==");
+        assert!(complete);
+        let (buffer, complete) = read_resp(&mut std::io::Cursor::new("true . M")).unwrap();
+        assert_eq!(buffer, b"true . M");
+        assert!(!complete);
+        assert!(read_resp(&mut std::io::Cursor::new("")).unwrap().0.is_empty());
+        assert!(IDEMPOTENT_METHODS.contains(&"get_block"));
+        assert!(!IDEMPOTENT_METHODS.contains(&"send"));
+
+        let e = timed_out(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(timed_out(std::io::Error::from(std::io::ErrorKind::BrokenPipe)).kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
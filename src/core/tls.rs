@@ -0,0 +1,458 @@
+use std::{collections::BTreeMap, net::{SocketAddr, TcpStream}, sync::Mutex, time::Duration};
+
+use base64::{engine::general_purpose, Engine as _};
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    ssl::{SslConnector, SslConnectorBuilder, SslFiletype, SslMethod, SslRef, SslStream, SslVersion},
+    x509::{store::X509Lookup, verify::X509VerifyFlags, X509NameRef, X509Ref, X509},
+};
+use serde::Serialize;
+
+use crate::core::{client::ClientConfig, endpoint::{self, Endpoint}};
+
+/// Prefix of SPKI fingerprints, as in HPKP pins.
+const PIN_PREFIX: &str = "sha256/";
+/// How long a connection may go without progress on a read or write, so a server that accepts
+/// and then never answers can't hang the client.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serializes access to the known servers files of concurrent block requests.
+static KNOWN_SERVERS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Certificate the client presents to servers that authenticate clients.
+/// Passphrases are for encrypted keys and PKCS#12 bundles.
+#[derive(Debug, Clone)]
+pub enum ClientIdentity {
+    Pem {
+        cert_file: String,
+        key_file: String,
+        passphrase: Option<String>,
+    },
+    Pkcs12 {
+        file: String,
+        passphrase: Option<String>,
+    },
+}
+
+fn tls_error(message: String) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, message))
+}
+
+fn read(kind: &str, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    std::fs::read(path).map_err(|e| tls_error(format!("read {} {} failed: {}", kind, path, e)))
+}
+
+pub fn connector(config: &ClientConfig) -> Result<SslConnector, Box<dyn std::error::Error>> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if config.system_trust {
+        builder.set_default_verify_paths()?;
+    }
+    if !config.cert_file.is_empty() {
+        builder.set_ca_file(&config.cert_file)?;
+    }
+    if let Some(ca_dir) = &config.ca_dir {
+        add_ca_dir(&mut builder, ca_dir)?;
+    }
+    if let Some(identity) = &config.client_identity {
+        set_identity(&mut builder, identity)?;
+    }
+
+    builder.set_min_proto_version(config.tls_min_version)?;
+    builder.set_max_proto_version(config.tls_max_version)?;
+    if let Some(cipher_list) = &config.cipher_list {
+        builder
+            .set_cipher_list(cipher_list)
+            .map_err(|e| tls_error(format!("no usable cipher in {}: {}", cipher_list, e)))?;
+    }
+    if let Some(ciphersuites) = &config.ciphersuites {
+        builder
+            .set_ciphersuites(ciphersuites)
+            .map_err(|e| tls_error(format!("no usable TLS 1.3 ciphersuite in {}: {}", ciphersuites, e)))?;
+    }
+    if !config.crl_files.is_empty() {
+        add_crls(&mut builder, &config.crl_files)?;
+    }
+    Ok(builder.build())
+}
+
+/// Connects to the first reachable server and runs the handshake, including pin checks.
+/// The certificate is verified against `domain` if set, else against the endpoint's host name or IP.
+pub fn connect(config: &ClientConfig) -> Result<(Endpoint, SocketAddr, SslStream<TcpStream>), Box<dyn std::error::Error>> {
+    let has_identity = config.client_identity.is_some();
+    let connector = connector(config)?;
+
+    let (endpoint, addr, stream) = endpoint::dial(&config.endpoints, &config.proxies)?;
+    // kept for the life of the connection, pooled or not
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let server_name = config.domain.as_deref().or(endpoint.server_name()).unwrap_or(&endpoint.host);
+    // openssl skips SNI and checks the certificate's IP addresses when given an IP literal
+    let ssl_stream = connector
+        .connect(server_name, stream)
+        .map_err(|e| explain_error(e.to_string().into(), has_identity))?;
+    check_peer(config, &endpoint, ssl_stream.ssl())?;
+    Ok((endpoint, addr, ssl_stream))
+}
+
+/// Parses `1.2` or `1.3`, optionally written `tls1.3`; older versions are not offered.
+pub fn parse_tls_version(version: &str) -> Result<SslVersion, String> {
+    match version.to_ascii_lowercase().trim_start_matches("tls").trim_start_matches('v') {
+        "1.2" => Ok(SslVersion::TLS1_2),
+        "1.3" => Ok(SslVersion::TLS1_3),
+        _ => Err(format!("illegal TLS version {}, use 1.2 or 1.3", version)),
+    }
+}
+
+/// Loads the CRLs and checks every certificate of the server chain against them.
+/// A chain whose issuer has no CRL loaded is rejected, as openssl can't tell it isn't revoked.
+fn add_crls(builder: &mut SslConnectorBuilder, crl_files: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let store = builder.cert_store_mut();
+    let lookup = store.add_lookup(X509Lookup::file())?;
+    for crl_file in crl_files {
+        let pem = read("CRL", crl_file)?.starts_with(b"-----BEGIN");
+        let file_type = if pem { SslFiletype::PEM } else { SslFiletype::ASN1 };
+        lookup
+            .load_crl_file(crl_file, file_type)
+            .map_err(|e| tls_error(format!("load CRL {} failed: {}", crl_file, e)))?;
+    }
+    store.set_flags(X509VerifyFlags::CRL_CHECK | X509VerifyFlags::CRL_CHECK_ALL)?;
+    Ok(())
+}
+
+/// Trusts every certificate in the PEM files of `ca_dir`; unlike openssl's hashed
+/// directories this needs no `c_rehash`.
+fn add_ca_dir(builder: &mut SslConnectorBuilder, ca_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = std::fs::read_dir(ca_dir).map_err(|e| tls_error(format!("read CA directory {} failed: {}", ca_dir, e)))?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        // skip whatever isn't PEM, e.g. a README next to the bundles
+        let certs = match std::fs::read(&path).map(|pem| X509::stack_from_pem(&pem)) {
+            Ok(Ok(certs)) => certs,
+            _ => {
+                tracing::debug!("skipping {} in CA directory, not PEM", path.display());
+                continue;
+            },
+        };
+        for cert in certs {
+            builder.cert_store_mut().add_cert(cert)?;
+        }
+    }
+    Ok(())
+}
+
+fn set_identity(builder: &mut SslConnectorBuilder, identity: &ClientIdentity) -> Result<(), Box<dyn std::error::Error>> {
+    let (cert, chain, key, source) = match identity {
+        ClientIdentity::Pem { cert_file, key_file, passphrase } => {
+            let mut certs = X509::stack_from_pem(&read("client certificate", cert_file)?)
+                .map_err(|e| tls_error(format!("client certificate {} is not PEM: {}", cert_file, e)))?
+                .into_iter();
+            let cert = certs
+                .next()
+                .ok_or_else(|| tls_error(format!("no certificate in {}", cert_file)))?;
+            let key = read_pem_key(key_file, passphrase.as_deref())?;
+            (cert, certs.collect(), key, format!("{} and {}", cert_file, key_file))
+        },
+        ClientIdentity::Pkcs12 { file, passphrase } => {
+            let parsed = Pkcs12::from_der(&read("PKCS#12 bundle", file)?)
+                .and_then(|pkcs12| pkcs12.parse2(passphrase.as_deref().unwrap_or("")))
+                .map_err(|e| tls_error(format!("open PKCS#12 bundle {} failed, wrong passphrase? {}", file, e)))?;
+            let cert = parsed.cert.ok_or_else(|| tls_error(format!("no certificate in {}", file)))?;
+            let key = parsed.pkey.ok_or_else(|| tls_error(format!("no private key in {}", file)))?;
+            let chain: Vec<X509> = parsed.ca.map(|ca| ca.into_iter().collect()).unwrap_or_default();
+            (cert, chain, key, file.clone())
+        },
+    };
+
+    builder.set_certificate(&cert)?;
+    for cert in chain {
+        builder.add_extra_chain_cert(cert)?;
+    }
+    // openssl refuses a mismatched key either when setting it or when checking it
+    builder
+        .set_private_key(&key)
+        .and_then(|_| builder.check_private_key())
+        .map_err(|_| tls_error(format!("client private key does not match the certificate in {}", source)))?;
+    Ok(())
+}
+
+fn read_pem_key(key_file: &str, passphrase: Option<&str>) -> Result<PKey<Private>, Box<dyn std::error::Error>> {
+    let pem = read("client key", key_file)?;
+    let key = match passphrase {
+        Some(passphrase) => PKey::private_key_from_pem_passphrase(&pem, passphrase.as_bytes()),
+        None => PKey::private_key_from_pem(&pem),
+    };
+    key.map_err(|e| match passphrase {
+        Some(_) => tls_error(format!("client key {} could not be decrypted, wrong passphrase? {}", key_file, e)),
+        None => tls_error(format!("client key {} is not a PEM key or needs a passphrase: {}", key_file, e)),
+    })
+}
+
+/// The pin of a certificate: SHA-256 of its subject public key info, `sha256/<base64>`.
+pub fn spki_fingerprint(cert: &X509Ref) -> Result<String, Box<dyn std::error::Error>> {
+    let spki = cert.public_key()?.public_key_to_der()?;
+    let digest = hash(MessageDigest::sha256(), &spki)?;
+    Ok(format!("{}{}", PIN_PREFIX, general_purpose::STANDARD.encode(digest)))
+}
+
+/// Accepts pins as `sha256/<base64>` or as hex, optionally colon separated, and returns the `sha256/` form.
+pub fn normalize_pin(pin: &str) -> Result<String, String> {
+    let digest = match pin.strip_prefix(PIN_PREFIX) {
+        Some(b64) => general_purpose::STANDARD.decode(b64).map_err(|_| format!("illegal pin {}", pin))?,
+        None => {
+            let hex = pin.replace(':', "");
+            if !hex.len().is_multiple_of(2) {
+                return Err(format!("illegal pin {}", pin));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| format!("illegal pin {}", pin))?
+        },
+    };
+
+    if digest.len() != 32 {
+        return Err(format!("pin {} is not a SHA-256 digest", pin));
+    }
+    Ok(format!("{}{}", PIN_PREFIX, general_purpose::STANDARD.encode(digest)))
+}
+
+/// Checks the server's key against the configured pins and, in trust-on-first-use mode,
+/// against the key recorded for this profile the first time the server was seen.
+pub fn check_peer(config: &ClientConfig, endpoint: &Endpoint, ssl: &SslRef) -> Result<(), Box<dyn std::error::Error>> {
+    if config.pins.is_empty() && !config.trust_on_first_use {
+        return Ok(());
+    }
+
+    let cert = ssl
+        .peer_certificate()
+        .ok_or_else(|| tls_error("server sent no certificate".to_string()))?;
+    let fingerprint = spki_fingerprint(&cert)?;
+
+    if !config.pins.is_empty() && !config.pins.contains(&fingerprint) {
+        return Err(tls_error(format!("server key {} matches none of the pinned keys", fingerprint)));
+    }
+
+    if config.trust_on_first_use {
+        let server = endpoint.to_string();
+        let path = format!("{}/known_servers/{}.json", config.state_dir, config.profile);
+        check_known_server(&path, &server, &fingerprint)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Known {
+    Recorded,
+    Matches,
+    Changed(String),
+}
+
+fn remember(known: &mut BTreeMap<String, String>, server: &str, fingerprint: &str) -> Known {
+    match known.get(server) {
+        None => {
+            known.insert(server.to_string(), fingerprint.to_string());
+            Known::Recorded
+        },
+        Some(recorded) if recorded == fingerprint => Known::Matches,
+        Some(recorded) => Known::Changed(recorded.clone()),
+    }
+}
+
+fn check_known_server(path: &str, server: &str, fingerprint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let _guard = KNOWN_SERVERS_LOCK.lock().unwrap();
+
+    let mut known: BTreeMap<String, String> = match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    match remember(&mut known, server, fingerprint) {
+        Known::Matches => Ok(()),
+        Known::Recorded => {
+            if let Some(dir) = std::path::Path::new(path).parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(path, serde_json::to_vec_pretty(&known)?)?;
+            tracing::warn!("first connection to {}, trusting its key {}", server, fingerprint);
+            Ok(())
+        },
+        Known::Changed(recorded) => {
+            tracing::warn!("someone may be intercepting the connection to {}, or the server got a new key", server);
+            Err(tls_error(format!(
+                "server key of {} changed from {} to {}, refusing to connect; if the change is expected, remove {} from {}",
+                server, recorded, fingerprint, server, path
+            )))
+        },
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub fingerprint: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TlsInfo {
+    pub server: String,
+    pub address: String,
+    pub version: String,
+    pub cipher: String,
+    pub chain: Vec<CertInfo>,
+}
+
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().as_utf8().map(|value| value.to_string()).unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Connects to the server and describes the negotiated session and the certificates it sent.
+pub fn tls_info(config: &ClientConfig) -> Result<TlsInfo, Box<dyn std::error::Error>> {
+    let (endpoint, _, ssl_stream) = connect(config)?;
+    let ssl = ssl_stream.ssl();
+
+    let mut chain = Vec::new();
+    for cert in ssl.peer_cert_chain().into_iter().flatten() {
+        chain.push(CertInfo {
+            subject: name_to_string(cert.subject_name()),
+            issuer: name_to_string(cert.issuer_name()),
+            not_before: cert.not_before().to_string(),
+            not_after: cert.not_after().to_string(),
+            fingerprint: spki_fingerprint(cert)?,
+        });
+    }
+
+    Ok(TlsInfo {
+        server: endpoint.to_string(),
+        address: ssl_stream.get_ref().peer_addr()?.to_string(),
+        version: ssl.version_str().to_string(),
+        cipher: ssl.current_cipher().map_or("none".to_string(), |cipher| cipher.name().to_string()),
+        chain,
+    })
+}
+
+/// Turns TLS alerts about client certificates into something a user can act on.
+/// With TLS 1.3 the server only rejects the certificate after the handshake, so this also applies to reads.
+pub fn explain_error(e: Box<dyn std::error::Error>, has_identity: bool) -> Box<dyn std::error::Error> {
+    let message = e.to_string();
+    if !message.contains("alert") {
+        return e;
+    }
+
+    if message.contains("certificate required") && !has_identity {
+        return tls_error(format!(
+            "server requires a client certificate, pass --client-cert and --client-key or --client-p12: {}",
+            message
+        ));
+    }
+    if has_identity && (message.contains("certificate") || message.contains("unknown ca")) {
+        return tls_error(format!("server rejected the client certificate: {}", message));
+    }
+    e
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, rsa::Rsa, symm::Cipher, x509::X509NameBuilder};
+
+    use super::*;
+
+    fn self_signed() -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "client").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    #[test]
+    fn test_normalize_pin() {
+        let b64 = format!("sha256/{}", general_purpose::STANDARD.encode([0xab; 32]));
+        assert_eq!(normalize_pin(&b64), Ok(b64.clone()));
+        assert_eq!(normalize_pin(&"ab".repeat(32)), Ok(b64.clone()));
+        assert_eq!(normalize_pin(&["AB"; 32].join(":")), Ok(b64));
+        assert!(normalize_pin("sha256/AAAA").is_err());
+        assert!(normalize_pin("xyz").is_err());
+    }
+
+    #[test]
+    fn test_parse_tls_version() {
+        assert_eq!(parse_tls_version("1.3"), Ok(SslVersion::TLS1_3));
+        assert_eq!(parse_tls_version("TLSv1.2"), Ok(SslVersion::TLS1_2));
+        assert!(parse_tls_version("1.0").is_err());
+    }
+
+    #[test]
+    fn test_remember() {
+        let mut known = BTreeMap::new();
+        assert_eq!(remember(&mut known, "a:1", "sha256/x"), Known::Recorded);
+        assert_eq!(remember(&mut known, "a:1", "sha256/x"), Known::Matches);
+        assert_eq!(remember(&mut known, "a:1", "sha256/y"), Known::Changed("sha256/x".to_string()));
+        assert_eq!(remember(&mut known, "b:1", "sha256/y"), Known::Recorded);
+    }
+
+    #[test]
+    fn test_set_identity() {
+        let dir = std::env::temp_dir().join(format!("client_tls_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        let (cert, key) = self_signed();
+        let (_, other_key) = self_signed();
+        std::fs::write(path("cert.pem"), cert.to_pem().unwrap()).unwrap();
+        std::fs::write(path("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(path("other.pem"), other_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let encrypted = key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret").unwrap();
+        std::fs::write(path("encrypted.pem"), encrypted).unwrap();
+        let pkcs12 = Pkcs12::builder().name("client").pkey(&key).cert(&cert).build2("secret").unwrap();
+        std::fs::write(path("client.p12"), pkcs12.to_der().unwrap()).unwrap();
+
+        let check = |identity: ClientIdentity| {
+            let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+            set_identity(&mut builder, &identity).map_err(|e| e.to_string())
+        };
+        let pem = |key: &str, passphrase: Option<&str>| ClientIdentity::Pem {
+            cert_file: path("cert.pem"),
+            key_file: path(key),
+            passphrase: passphrase.map(str::to_string),
+        };
+        let p12 = |passphrase: &str| ClientIdentity::Pkcs12 {
+            file: path("client.p12"),
+            passphrase: Some(passphrase.to_string()),
+        };
+
+        assert!(check(pem("key.pem", None)).is_ok());
+        assert!(check(pem("encrypted.pem", Some("secret"))).is_ok());
+        assert!(check(pem("encrypted.pem", Some("wrong"))).unwrap_err().contains("wrong passphrase"));
+        assert!(check(pem("other.pem", None)).unwrap_err().contains("does not match"));
+        assert!(check(p12("secret")).is_ok());
+        assert!(check(p12("wrong")).unwrap_err().contains("wrong passphrase"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
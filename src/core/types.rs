@@ -0,0 +1,240 @@
+use std::{fmt, iter::Sum, num::TryFromIntError, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A file on the server, as handed out by `presend`.
+/// The wire carries it as a plain number; negative or oversized ids are rejected when a response is parsed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct FileId(u32);
+
+impl FileId {
+    pub const fn new(id: u32) -> Self {
+        FileId(id)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for FileId {
+    fn from(id: u32) -> Self {
+        FileId(id)
+    }
+}
+
+impl TryFrom<i64> for FileId {
+    type Error = TryFromIntError;
+
+    fn try_from(id: i64) -> Result<Self, Self::Error> {
+        u32::try_from(id).map(FileId)
+    }
+}
+
+impl From<FileId> for i64 {
+    fn from(id: FileId) -> Self {
+        id.0 as i64
+    }
+}
+
+impl FromStr for FileId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(FileId).map_err(|_| format!("illegal file id {}", s))
+    }
+}
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A block of a file. Blocks are numbered from 0 in file order when uploaded;
+/// the server's handle for fetching one back is a `BlockHandle`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(transparent)]
+pub struct BlockId(u64);
+
+impl BlockId {
+    pub const fn new(id: u64) -> Self {
+        BlockId(id)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The block following this one in file order.
+    pub const fn next(self) -> Self {
+        BlockId(self.0 + 1)
+    }
+}
+
+impl From<u64> for BlockId {
+    fn from(id: u64) -> Self {
+        BlockId(id)
+    }
+}
+
+impl FromStr for BlockId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(BlockId).map_err(|_| format!("illegal block id {}", s))
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The server's handle for a stored block, as listed by `get_block_ids` and passed to `get_block`.
+/// It says nothing about where the block goes in the file, that is its `BlockId`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct BlockHandle(u64);
+
+impl BlockHandle {
+    pub const fn new(handle: u64) -> Self {
+        BlockHandle(handle)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for BlockHandle {
+    fn from(handle: u64) -> Self {
+        BlockHandle(handle)
+    }
+}
+
+impl fmt::Display for BlockHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A size in bytes, of a file, a block or a quota. Always 64-bit so files over 4 GiB keep their size
+/// on every platform; converting to `usize` for an in-memory buffer is checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(transparent)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub const fn new(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The length of an in-memory buffer.
+    pub const fn of_len(len: usize) -> Self {
+        ByteSize(len as u64)
+    }
+
+    pub fn checked_add(self, other: ByteSize) -> Option<ByteSize> {
+        self.0.checked_add(other.0).map(ByteSize)
+    }
+}
+
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+}
+
+impl TryFrom<i64> for ByteSize {
+    type Error = TryFromIntError;
+
+    fn try_from(bytes: i64) -> Result<Self, Self::Error> {
+        u64::try_from(bytes).map(ByteSize)
+    }
+}
+
+impl TryFrom<ByteSize> for usize {
+    type Error = TryFromIntError;
+
+    fn try_from(size: ByteSize) -> Result<Self, Self::Error> {
+        usize::try_from(size.0)
+    }
+}
+
+impl TryFrom<ByteSize> for u32 {
+    type Error = TryFromIntError;
+
+    fn try_from(size: ByteSize) -> Result<Self, Self::Error> {
+        u32::try_from(size.0)
+    }
+}
+
+/// The total of some sizes, `None` if it does not fit in 64 bits.
+impl Sum<ByteSize> for Option<ByteSize> {
+    fn sum<I: Iterator<Item = ByteSize>>(mut iter: I) -> Self {
+        iter.try_fold(ByteSize(0), ByteSize::checked_add)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIVE_GIB: u64 = 5 * 1024 * 1024 * 1024;
+
+    #[test]
+    fn test_file_id() {
+        assert_eq!(FileId::try_from(42i64), Ok(FileId::new(42)));
+        assert!(FileId::try_from(-1i64).is_err());
+        assert!(FileId::try_from(1i64 << 32).is_err());
+        assert_eq!("7".parse::<FileId>(), Ok(FileId::new(7)));
+        assert!("-7".parse::<FileId>().is_err());
+
+        assert_eq!(serde_json::from_str::<FileId>("4294967295").unwrap(), FileId::new(u32::MAX));
+        assert!(serde_json::from_str::<FileId>("-1").is_err());
+        assert!(serde_json::from_str::<FileId>("4294967296").is_err());
+    }
+
+    #[test]
+    fn test_byte_size_over_4gib() {
+        let size: ByteSize = serde_json::from_str(&FIVE_GIB.to_string()).unwrap();
+        assert_eq!(size.get(), FIVE_GIB);
+        assert_eq!(serde_json::to_string(&size).unwrap(), FIVE_GIB.to_string());
+        assert!(serde_json::from_str::<ByteSize>("-1").is_err());
+
+        assert!(u32::try_from(size).is_err());
+        assert_eq!(u32::try_from(ByteSize::new(u32::MAX as u64)), Ok(u32::MAX));
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(usize::try_from(size), Ok(FIVE_GIB as usize));
+        #[cfg(target_pointer_width = "32")]
+        assert!(usize::try_from(size).is_err());
+
+        let blocks = vec![ByteSize::new(u32::MAX as u64); 2];
+        assert_eq!(blocks.into_iter().sum::<Option<ByteSize>>(), Some(ByteSize::new(2 * u32::MAX as u64)));
+        assert_eq!([ByteSize::new(u64::MAX), ByteSize::new(1)].into_iter().sum::<Option<ByteSize>>(), None);
+        assert_eq!(ByteSize::new(u64::MAX).checked_add(ByteSize::new(1)), None);
+        assert!(ByteSize::try_from(-1i64).is_err());
+    }
+
+    #[test]
+    fn test_block_id() {
+        let id: BlockId = serde_json::from_str(&(FIVE_GIB / 1024).to_string()).unwrap();
+        assert_eq!(id.next().get(), FIVE_GIB / 1024 + 1);
+        assert_eq!("4294967296".parse::<BlockId>(), Ok(BlockId::new(1 << 32)));
+        assert!("-3".parse::<BlockId>().is_err());
+        assert!(serde_json::from_str::<BlockId>("-3").is_err());
+    }
+}
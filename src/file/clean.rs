@@ -0,0 +1,223 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::core::types::{BlockId, FileId};
+
+/// Written to the state directory while a download keeps temp files in `target_path`.
+#[derive(Serialize, Deserialize, Debug)]
+struct TransferJournal {
+    target_path: String,
+    prefix: String,
+    pid: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct CleanReport {
+    pub removed_files: Vec<String>,
+    pub removed_journals: usize,
+    pub skipped_active: usize,
+}
+
+fn journal_dir(state_dir: &str) -> String {
+    format!("{}/transfers", state_dir)
+}
+
+/// Records a download in progress; returns the journal path, or `None` if it couldn't be written.
+pub async fn begin_transfer(state_dir: &str, target_path: &str, prefix: &str) -> Option<String> {
+    let dir = journal_dir(state_dir);
+    let journal = TransferJournal {
+        target_path: target_path.to_string(),
+        prefix: prefix.to_string(),
+        pid: std::process::id(),
+    };
+    let path = format!("{}/{}.json", dir, prefix);
+
+    let rst = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(&path, serde_json::to_vec(&journal)?).await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    }
+    .await;
+
+    match rst {
+        Ok(_) => Some(path),
+        Err(e) => {
+            tracing::warn!("write transfer journal {} failed: {:?}", path, e);
+            None
+        }
+    }
+}
+
+pub async fn end_transfer(journal: Option<String>) {
+    if let Some(path) = journal {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+/// Splits a temp artifact name into its `{file_id}_{uuid}` prefix.
+/// Artifacts are block files `{prefix}_{block_id}` and joined files `.{name}.{prefix}.part`.
+pub fn artifact_prefix(name: &str) -> Option<&str> {
+    let prefix = match name.strip_suffix(".part") {
+        Some(rest) if name.starts_with('.') => rest.rsplit_once('.')?.1,
+        Some(_) => return None,
+        None => {
+            let (prefix, block_id) = name.rsplit_once('_')?;
+            block_id.parse::<BlockId>().ok()?;
+            prefix
+        },
+    };
+
+    let (file_id, uuid) = prefix.split_once('_')?;
+    file_id.parse::<FileId>().ok()?;
+    Uuid::parse_str(uuid).ok()?;
+    Some(prefix)
+}
+
+/// Removes every artifact of `prefix` from `dir`, returning the removed paths.
+pub async fn remove_artifacts(dir: &str, prefix: &str) -> Vec<String> {
+    remove_matching(dir, |name, _| artifact_prefix(name) == Some(prefix)).await
+}
+
+async fn remove_matching<F>(dir: &str, matches: F) -> Vec<String>
+where
+    F: Fn(&str, SystemTime) -> bool,
+{
+    let mut removed = Vec::new();
+    let mut dir_entries = match tokio::fs::read_dir(dir).await {
+        Ok(dir_entries) => dir_entries,
+        Err(_) => return removed,
+    };
+
+    while let Ok(Some(entry)) = dir_entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let modified = match entry.metadata().await.and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if !matches(&name, modified) {
+            continue;
+        }
+
+        let path = format!("{}/{}", dir, name);
+        if tokio::fs::remove_file(&path).await.is_ok() {
+            removed.push(path);
+        }
+    }
+
+    removed
+}
+
+fn process_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+async fn read_journals(state_dir: &str) -> Vec<(String, TransferJournal)> {
+    let dir = journal_dir(state_dir);
+    let mut journals = Vec::new();
+    let mut dir_entries = match tokio::fs::read_dir(&dir).await {
+        Ok(dir_entries) => dir_entries,
+        Err(_) => return journals,
+    };
+
+    while let Ok(Some(entry)) = dir_entries.next_entry().await {
+        let path = entry.path().to_string_lossy().to_string();
+        match tokio::fs::read(&path).await.map(|data| serde_json::from_slice(&data)) {
+            Ok(Ok(journal)) => journals.push((path, journal)),
+            // a journal we can't read is of no use to anyone
+            _ => {
+                let _ = tokio::fs::remove_file(&path).await;
+            },
+        }
+    }
+
+    journals
+}
+
+/// Cleans up after journaled downloads whose process is gone.
+/// Returns the prefixes of downloads still running and every journaled target directory.
+async fn sweep_journals(state_dir: &str, report: &mut CleanReport) -> (HashSet<String>, Vec<String>) {
+    let mut active = HashSet::new();
+    let mut targets = Vec::new();
+
+    for (path, journal) in read_journals(state_dir).await {
+        if !targets.contains(&journal.target_path) {
+            targets.push(journal.target_path.clone());
+        }
+        if process_alive(journal.pid) {
+            report.skipped_active += 1;
+            active.insert(journal.prefix);
+            continue;
+        }
+
+        let removed = remove_artifacts(&journal.target_path, &journal.prefix).await;
+        report.removed_files.extend(removed);
+        let _ = tokio::fs::remove_file(path).await;
+        report.removed_journals += 1;
+    }
+
+    (active, targets)
+}
+
+/// Removes temp artifacts left behind by downloads that are no longer running:
+/// everything recorded in journals of dead processes, plus unjournaled artifacts
+/// older than `older_than` in `dirs` and in every journaled target directory.
+/// Journals are kept in `state_dir`.
+pub async fn clean(state_dir: &str, dirs: Vec<String>, older_than: Duration) -> CleanReport {
+    let mut report = CleanReport::default();
+    let (active, targets) = sweep_journals(state_dir, &mut report).await;
+
+    let mut scan_dirs = dirs;
+    for target in targets {
+        if !scan_dirs.contains(&target) {
+            scan_dirs.push(target);
+        }
+    }
+
+    let now = SystemTime::now();
+    for dir in scan_dirs {
+        let removed = remove_matching(&dir, |name, modified| {
+            let old_enough = now.duration_since(modified).unwrap_or_default() >= older_than;
+            old_enough && artifact_prefix(name).is_some_and(|prefix| !active.contains(prefix))
+        })
+        .await;
+        report.removed_files.extend(removed);
+    }
+
+    report
+}
+
+/// Startup cleanup for sessions that crashed mid-download.
+pub async fn clean_crashed(state_dir: &str) -> CleanReport {
+    let mut report = CleanReport::default();
+    sweep_journals(state_dir, &mut report).await;
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_artifact_prefix() {
+        let prefix = "26_67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(artifact_prefix(&format!("{}_0", prefix)), Some(prefix));
+        assert_eq!(artifact_prefix(&format!("{}_12", prefix)), Some(prefix));
+        assert_eq!(artifact_prefix(&format!(".report.v2.pdf.{}.part", prefix)), Some(prefix));
+        assert_eq!(artifact_prefix(&format!("{}_x", prefix)), None);
+        assert_eq!(artifact_prefix(&format!("report.{}.part", prefix)), None);
+        assert_eq!(artifact_prefix("26_not-a-uuid_0"), None);
+        assert_eq!(artifact_prefix("my_holiday_photo.jpg"), None);
+
+        // ids past the 32-bit signed range of older servers
+        let prefix = "3000000000_67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(artifact_prefix(&format!("{}_4294967296", prefix)), Some(prefix));
+        assert_eq!(artifact_prefix(&format!(".a.{}.part", prefix)), Some(prefix));
+        assert_eq!(artifact_prefix("-1_67e55044-10b1-426f-9247-bb680e5fe0c8_0"), None);
+    }
+}
@@ -1,25 +1,101 @@
-use std::sync::{Arc, Mutex};
-use crc_fast::{checksum,  checksum_file, CrcAlgorithm::Crc32IsoHdlc};
-use tokio::{io::AsyncWriteExt as _, sync::Semaphore};
+use std::{collections::BTreeMap, path::{Component, Path}, sync::{Arc, Mutex}};
+use crc_fast::{checksum,  checksum_file, CrcAlgorithm::Crc32IsoHdlc, Digest};
+use futures_util::StreamExt as _;
+use tokio::{io::{AsyncWrite, AsyncWriteExt as _}, sync::Semaphore};
+use tracing::Instrument as _;
 use uuid::Uuid;
 
-use crate::{control::ControlBlock, core::{biz, req::async_debug}};
+use crate::{
+    control::ControlBlock,
+    core::{biz::{self, FileInfo}, client::Context, types::{BlockHandle, BlockId, FileId}},
+    file::{clean, preflight, transfer::Transfer},
+    user::authorization::{self, current},
+};
 
-fn make_prefix(file_id: i32) -> String {
+fn make_prefix(file_id: FileId) -> String {
     let uuid = Uuid::new_v4();
     format!("{}_{}", file_id, uuid)
 }
 
+/// What to do when the download target already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    #[default]
+    Fail,
+    Overwrite,
+    Skip,
+    Rename,
+}
+
+/// Downloads `file_id` into `target_path`; returns the written path, or `None` if skipped.
 pub async fn download(
+    ctx: &Context,
     block: ControlBlock,
-    file_id: i32,
+    file_id: FileId,
     target_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let block_ids = biz::get_block_ids(block.clone(), file_id).await?.block_ids;
+    policy: OverwritePolicy,
+    transfer: Transfer,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    download_to(ctx, block, file_id, target_path, None, policy, transfer).await
+}
 
-    let semaphore = Arc::new(Semaphore::new(16));
+/// Downloads `file_id` into `target_path`, saving it as `file_name` instead of the server-side name when given.
+/// The file is assembled under a temp name and only moved into place once its checksum passes.
+pub async fn download_to(
+    ctx: &Context,
+    block: ControlBlock,
+    file_id: FileId,
+    target_path: &str,
+    file_name: Option<&str>,
+    policy: OverwritePolicy,
+    transfer: Transfer,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let file_info = biz::get_file_info(ctx, file_id).await?;
+    tracing::debug!(?file_info, "downloading");
+    let file_name = sanitize_file_name(file_name.unwrap_or(&file_info.file_name))?;
+
+    let file_name = match target_name(target_path, &file_name, policy).await? {
+        Some(file_name) => file_name,
+        None => return Ok(None),
+    };
+
+    preflight::check_download(target_path, file_info.file_size).await?;
+    transfer.progress.set_total(file_info.file_size.get());
 
     let prefix = make_prefix(file_id);
+    let temp_name = format!(".{}.{}.part", file_name, prefix);
+
+    // the journal lets `clean` and the next startup find our temp files if we crash
+    let journal = clean::begin_transfer(&ctx.config().state_dir, target_path, &prefix).await;
+    let rst = fetch_and_join(ctx, block, &file_info, target_path, &prefix, &temp_name, transfer).await;
+    if rst.is_err() {
+        clean::remove_artifacts(target_path, &prefix).await;
+    }
+    clean::end_transfer(journal).await;
+    rst?;
+
+    let final_path = format!("{}/{}", target_path, file_name);
+    tokio::fs::rename(format!("{}/{}", target_path, temp_name), &final_path).await?;
+
+    Ok(Some(final_path))
+}
+
+/// Fetches every block of the file into `target_path` and joins them into `temp_name`,
+/// verifying the file checksum.
+#[tracing::instrument(name = "download", skip_all, fields(file_id = %file_info.id))]
+async fn fetch_and_join(
+    ctx: &Context,
+    block: ControlBlock,
+    file_info: &FileInfo,
+    target_path: &str,
+    prefix: &str,
+    temp_name: &str,
+    transfer: Transfer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _keep_alive = authorization::keep_alive(ctx, &block);
+    let block_ids = biz::get_block_ids(ctx, current(ctx, &block), file_info.id).await?.block_ids;
+
+    let semaphore = Arc::new(Semaphore::new(16));
 
     let mutex_flag = Arc::new(Mutex::new(true));
 
@@ -27,29 +103,44 @@ pub async fn download(
         .iter()
         .map(|block_id| {
             let semaphore = semaphore.clone();
+            let ctx = ctx.clone();
             let block = block.clone();
             let block_id = *block_id;
-            let prefix = prefix.clone();
+            let prefix = prefix.to_owned();
             let target_path = target_path.to_owned();
+            let transfer = transfer.clone();
 
             let mutex_flag = mutex_flag.clone();
 
             tokio::task::spawn(async move {
                 let _permit = semaphore.acquire().await.unwrap();
                 let mut success = false;
-                for _ in 0..3 {
+                let mut reauthed = false;
+                for attempt in 1..=3 {
                     if !*mutex_flag.lock().unwrap() {
                         break;
                     }
 
-                    let block_use = block.clone();
-                    let rst = biz::get_block(block_use, block_id).await;
+                    let block_use = current(&ctx, &block);
+                    let rst = biz::get_block(&ctx, block_use.clone(), block_id)
+                        .instrument(tracing::debug_span!("attempt", attempt))
+                        .await;
+                    if rst.is_none() {
+                        tracing::warn!(attempt, "get block failed");
+                    }
+                    if rst.is_none() && !reauthed {
+                        reauthed = true;
+                        let _ = authorization::reauth(&ctx, &block_use).await;
+                    }
                     if let Some(resp) = rst {
                         let block_info = resp.block_info;
                         let block_data = resp.block_data;
+                        // the size is only known once the block is here, so hold back the next fetch instead
+                        transfer.throttle.take(block_data.len()).await;
 
                         let block_checksum = block_info.block_checksum;
                         if block_checksum != checksum(Crc32IsoHdlc, &block_data) as u32 {
+                            tracing::warn!(attempt, "block checksum mismatch");
                             continue;
                         }
 
@@ -63,6 +154,7 @@ pub async fn download(
 
                         match file.write_all(&block_data).await {
                             Ok(_) => {
+                                transfer.progress.add(block_data.len() as u64);
                                 success = true;
                                 break;
                             },
@@ -72,9 +164,10 @@ pub async fn download(
                 }
 
                 if !success {
+                    tracing::error!("giving up on block");
                     *mutex_flag.lock().unwrap() = false;
                 }
-            })
+            }.instrument(tracing::debug_span!("block", block_id = %block_id)))
         })
         .collect::<Vec<_>>();
 
@@ -83,27 +176,177 @@ pub async fn download(
     }
 
     let flag = *mutex_flag.lock().unwrap();
-    async_debug(format!("mutex_flag: {flag}")).await;
+    tracing::debug!(success = flag, "fetched blocks");
 
     if !flag {
         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Download failed")));
     }
 
-    let file_info = biz::get_file_info(file_id).await?;
-    async_debug(format!("{:?}", file_info)).await;
-    let file_name = file_info.file_name;
-    let file_checksum = file_info.file_checksum;
+    let block_vec = search_files_by_prefix(target_path, prefix).await?;
+
+    tracing::debug!(?block_vec, "joining blocks");
+    join_files(block_vec, target_path, temp_name).await?;
+    check_file(target_path, temp_name, file_info.file_checksum).await
+}
+
+/// Only a single plain path component is accepted as a local file name,
+/// so a server-supplied name can't escape the target directory.
+pub fn sanitize_file_name(file_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut components = Path::new(file_name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if !file_name.contains(['/', '\\', '\0']) => {
+            Ok(name.to_string_lossy().to_string())
+        },
+        _ => Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("refusing unsafe file name {:?}", file_name),
+        ))),
+    }
+}
+
+/// Applies `policy` to `file_name` in `target_path`; `None` means skip the download.
+async fn target_name(target_path: &str, file_name: &str, policy: OverwritePolicy) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if !tokio::fs::try_exists(format!("{}/{}", target_path, file_name)).await? {
+        return Ok(Some(file_name.to_string()));
+    }
+
+    match policy {
+        OverwritePolicy::Overwrite => Ok(Some(file_name.to_string())),
+        OverwritePolicy::Skip => Ok(None),
+        OverwritePolicy::Fail => Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{}/{} already exists, use --overwrite, --skip-existing or --rename", target_path, file_name),
+        ))),
+        OverwritePolicy::Rename => {
+            let (stem, ext) = match file_name.rsplit_once('.') {
+                Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+                _ => (file_name, String::new()),
+            };
+            let mut n = 1;
+            loop {
+                let candidate = format!("{} ({}){}", stem, n, ext);
+                if !tokio::fs::try_exists(format!("{}/{}", target_path, candidate)).await? {
+                    return Ok(Some(candidate));
+                }
+                n += 1;
+            }
+        },
+    }
+}
 
-    let block_vec = search_files_by_prefix(target_path, prefix.as_str()).await?;
+/// Blocks fetched at once when streaming.
+const STREAM_FETCHES: usize = 16;
+/// Out-of-order blocks held back while waiting for an earlier one.
+const MAX_REORDER_BLOCKS: usize = 64;
 
-    async_debug(format!("{:?}", block_vec)).await;
-    join_files(block_vec.clone(), target_path, &format!("{}", file_name)).await?;
+/// Writes `file_id` to `writer` in block order without touching disk, e.g. to stdout.
+/// The file checksum can only be verified after everything has been written.
+#[tracing::instrument(name = "download", skip(ctx, block, file_id, writer, transfer), fields(file_id = %file_id))]
+pub async fn download_stream<W>(
+    ctx: &Context,
+    block: ControlBlock,
+    file_id: FileId,
+    writer: &mut W,
+    transfer: Transfer,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    W: AsyncWrite + Unpin,
+{
+    let _keep_alive = authorization::keep_alive(ctx, &block);
+    let file_info = biz::get_file_info(ctx, file_id).await?;
+    transfer.progress.set_total(file_info.file_size.get());
+    let block_ids = biz::get_block_ids(ctx, current(ctx, &block), file_id).await?.block_ids;
+
+    let fetches = futures_util::stream::iter(block_ids)
+        .map(|block_id| {
+            let ctx = ctx.clone();
+            let block = block.clone();
+            let transfer = transfer.clone();
+            async move {
+                match fetch_block(&ctx, block, block_id, &transfer).await {
+                    Some(resp) => Ok((resp.block_info.block_id, resp.block_data)),
+                    None => Err(std::io::Error::other(format!("fetch block {} failed", block_id))),
+                }
+            }
+        })
+        .buffered(STREAM_FETCHES);
 
-    check_file(target_path, &format!("{}", file_name), file_checksum).await?;
+    let crc32 = write_in_order(fetches, writer, &transfer).await?;
+    if crc32 != file_info.file_checksum {
+        return Err(Box::new(std::io::Error::other(format!("check_file failed, {} vs {}", crc32, file_info.file_checksum))));
+    }
 
     Ok(())
 }
 
+/// Writes blocks that may arrive out of order to `writer` in block order, starting at block 0,
+/// and returns the checksum of everything written.
+async fn write_in_order<S, W>(mut blocks: S, writer: &mut W, transfer: &Transfer) -> Result<u32, Box<dyn std::error::Error>>
+where
+    S: futures_util::Stream<Item = std::io::Result<(BlockId, Vec<u8>)>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut pending: BTreeMap<BlockId, Vec<u8>> = BTreeMap::new();
+    let mut next = BlockId::default();
+    let mut digest = Digest::new(Crc32IsoHdlc);
+
+    while let Some(fetched) = blocks.next().await {
+        let (block_id, data) = fetched?;
+        pending.insert(block_id, data);
+
+        while let Some(data) = pending.remove(&next) {
+            digest.update(&data);
+            writer.write_all(&data).await?;
+            transfer.progress.add(data.len() as u64);
+            next = next.next();
+        }
+
+        if pending.len() > MAX_REORDER_BLOCKS {
+            return Err(Box::new(std::io::Error::other(format!(
+                "block {} is missing or too far out of order to stream, download to a directory instead",
+                next
+            ))));
+        }
+    }
+    writer.flush().await?;
+
+    if !pending.is_empty() {
+        return Err(Box::new(std::io::Error::other(format!("block {} is missing", next))));
+    }
+
+    Ok(digest.finalize() as u32)
+}
+
+/// Fetches a block, retrying up to three times until its checksum matches.
+/// The token is refreshed once if the server doesn't answer, in case it expired mid-transfer.
+#[tracing::instrument(name = "block", skip(ctx, block, block_id, transfer), fields(block_id = %block_id))]
+async fn fetch_block(ctx: &Context, block: ControlBlock, block_id: BlockHandle, transfer: &Transfer) -> Option<biz::GetBlockResp> {
+    let mut reauthed = false;
+    for attempt in 1..=3 {
+        let block_use = current(ctx, &block);
+        let rst = biz::get_block(ctx, block_use.clone(), block_id)
+            .instrument(tracing::debug_span!("attempt", attempt))
+            .await;
+        if let Some(resp) = &rst {
+            transfer.throttle.take(resp.block_data.len()).await;
+        }
+        match rst {
+            Some(resp) if resp.block_info.block_checksum == checksum(Crc32IsoHdlc, &resp.block_data) as u32 => {
+                return Some(resp);
+            },
+            Some(_) => tracing::warn!(attempt, "block checksum mismatch"),
+            None if !reauthed => {
+                tracing::warn!(attempt, "get block failed");
+                reauthed = true;
+                let _ = authorization::reauth(ctx, &block_use).await;
+            },
+            None => tracing::warn!(attempt, "get block failed"),
+        }
+    }
+    tracing::error!("giving up on block");
+    None
+}
+
 async fn search_files_by_prefix(dir: &str, prefix: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut files = Vec::new();
     let mut dir_entries = tokio::fs::read_dir(dir).await?;
@@ -121,11 +364,11 @@ async fn search_files_by_prefix(dir: &str, prefix: &str) -> Result<Vec<String>,
     files.sort_by_key(|file| {
         let parts: Vec<&str> = file.split('_').collect();
         if let Some(last_part) = parts.last() {
-            if let Ok(num) = last_part.parse::<u32>() {
-                return num;
+            if let Ok(block_id) = last_part.parse::<BlockId>() {
+                return block_id;
             }
         }
-        u32::MAX
+        BlockId::new(u64::MAX)
     });
 
     Ok(files)
@@ -165,4 +408,46 @@ async fn test_search_by_prefix() {
     for strr in rst {
         println!("{}", strr)
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_sanitize_file_name() {
+    assert_eq!(sanitize_file_name("report.pdf").unwrap(), "report.pdf");
+    assert_eq!(sanitize_file_name(".bashrc").unwrap(), ".bashrc");
+    assert!(sanitize_file_name("../../.bashrc").is_err());
+    assert!(sanitize_file_name("/etc/passwd").is_err());
+    assert!(sanitize_file_name("a/b.txt").is_err());
+    assert!(sanitize_file_name("..\\x").is_err());
+    assert!(sanitize_file_name("..").is_err());
+    assert!(sanitize_file_name(".").is_err());
+    assert!(sanitize_file_name("").is_err());
+}
+
+#[tokio::test]
+async fn test_write_in_order() {
+    let block = |id: u64, data: &[u8]| Ok((BlockId::new(id), data.to_vec()));
+    let transfer = Transfer::default();
+
+    let mut out = Vec::new();
+    let blocks = futures_util::stream::iter(vec![block(2, b"cc"), block(0, b"a"), block(1, b"bb"), block(3, b"")]);
+    let crc32 = write_in_order(blocks, &mut out, &transfer).await.unwrap();
+    assert_eq!(out, b"abbcc");
+    assert_eq!(crc32, checksum(Crc32IsoHdlc, b"abbcc") as u32);
+    assert_eq!(transfer.progress.done(), 5);
+
+    // block 1 never arrives, so nothing after it may be written
+    let mut out = Vec::new();
+    let blocks = futures_util::stream::iter(vec![block(0, b"a"), block(2, b"cc")]);
+    let e = write_in_order(blocks, &mut out, &Transfer::default()).await.unwrap_err();
+    assert_eq!(e.to_string(), "block 1 is missing");
+    assert_eq!(out, b"a");
+
+    let mut out = Vec::new();
+    let blocks = (1..=MAX_REORDER_BLOCKS as u64 + 1).map(|id| block(id, b"x")).collect::<Vec<_>>();
+    let e = write_in_order(futures_util::stream::iter(blocks), &mut out, &Transfer::default()).await.unwrap_err();
+    assert!(e.to_string().starts_with("block 0 is missing or too far out of order"));
+
+    let mut out = Vec::new();
+    let blocks = futures_util::stream::iter(vec![block(0, b"a"), Err(std::io::Error::other("fetch block 9 failed"))]);
+    let e = write_in_order(blocks, &mut out, &Transfer::default()).await.unwrap_err();
+    assert_eq!(e.to_string(), "fetch block 9 failed");
+}
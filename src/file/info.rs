@@ -1,9 +1,358 @@
-use crate::{control::ControlBlock, core::biz::{self, ListFileResp}};
+use std::{collections::BTreeMap, str::FromStr};
 
-pub async fn list_file(filter: String) -> Result<ListFileResp, Box<dyn std::error::Error>> {
-    biz::list_file(filter).await
+use crc_fast::{checksum, CrcAlgorithm::Crc32IsoHdlc};
+use futures_util::StreamExt as _;
+use serde::Serialize;
+
+use crate::{
+    control::ControlBlock,
+    core::{biz::{self, FileBlock, FileInfo, ListFileResp}, client::Context, types::{BlockHandle, BlockId, ByteSize, FileId}},
+};
+
+/// What to do when a file name or pattern matches more than one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Disambiguation {
+    Newest,
+    Ask,
+    #[default]
+    Error,
+}
+
+impl FromStr for Disambiguation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(Disambiguation::Newest),
+            "ask" => Ok(Disambiguation::Ask),
+            "error" => Ok(Disambiguation::Error),
+            _ => Err(format!("unknown pick mode: {}", s)),
+        }
+    }
+}
+
+pub const LATEST_SUFFIX: &str = "@latest";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Time,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortKey::Name),
+            "size" => Ok(SortKey::Size),
+            "time" => Ok(SortKey::Time),
+            _ => Err(format!("unknown sort key: {}", s)),
+        }
+    }
+}
+
+pub async fn list_file(ctx: &Context, filter: String) -> Result<ListFileResp, Box<dyn std::error::Error>> {
+    biz::list_file(ctx, filter).await
+}
+
+pub async fn delete_file(ctx: &Context, block: ControlBlock, file_id: FileId) -> Result<(), Box<dyn std::error::Error>> {
+    biz::delete_file(ctx, block, file_id).await
+}
+
+/// Finds the files whose name equals `spec` or matches it as a glob, newest first.
+/// A trailing `@latest` is ignored here; callers use it to pick the first match.
+pub async fn find_files(ctx: &Context, spec: &str) -> Result<Vec<FileInfo>, Box<dyn std::error::Error>> {
+    let name = spec.strip_suffix(LATEST_SUFFIX).unwrap_or(spec);
+
+    // the server filter is a plain keyword, so narrow it down with the literal part of the pattern
+    let literal = name.split(['*', '?', '[']).next().unwrap_or("");
+    let mut files = match_name(biz::list_file(ctx, literal.to_string()).await?.file_info, name);
+
+    sort_files(&mut files, SortKey::Time, true);
+    Ok(files)
+}
+
+/// Exact names win over the glob, so `report[1].txt` finds that file and not `report1.txt`.
+/// A name that isn't a valid glob only matches exactly.
+fn match_name(files: Vec<FileInfo>, name: &str) -> Vec<FileInfo> {
+    let (exact, others): (Vec<_>, Vec<_>) = files.into_iter().partition(|file| file.file_name == name);
+    if !exact.is_empty() {
+        return exact;
+    }
+    match glob::Pattern::new(name) {
+        Ok(pattern) => others.into_iter().filter(|file| pattern.matches(&file.file_name)).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn sort_files(files: &mut [FileInfo], key: SortKey, reverse: bool) {
+    match key {
+        SortKey::Name => files.sort_by(|a, b| a.file_name.cmp(&b.file_name).then(a.id.cmp(&b.id))),
+        SortKey::Size => files.sort_by(|a, b| a.file_size.cmp(&b.file_size).then(a.id.cmp(&b.id))),
+        SortKey::Time => files.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id))),
+    }
+    if reverse {
+        files.reverse();
+    }
+}
+
+/// Sorts `files` and keeps up to `limit` of them from `offset` on, the page `list` shows.
+pub fn page_files(mut files: Vec<FileInfo>, key: SortKey, reverse: bool, offset: usize, limit: usize) -> Vec<FileInfo> {
+    sort_files(&mut files, key, reverse);
+    files.into_iter().skip(offset).take(limit).collect()
+}
+
+#[derive(Serialize, Debug)]
+pub struct BlockReport {
+    #[serde(flatten)]
+    pub block: FileBlock,
+    pub data_size: usize,
+    pub checksum_ok: bool,
+}
+
+#[derive(Debug)]
+pub struct FileDetails {
+    pub info: FileInfo,
+    pub block_ids: Vec<BlockHandle>,
+    pub blocks: Vec<BlockReport>,
+    /// Block ids the server listed but `get_block` couldn't return.
+    pub unreadable: Vec<BlockHandle>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileSummary {
+    #[serde(flatten)]
+    pub info: FileInfo,
+    pub block_count: usize,
+    pub unreadable_blocks: usize,
+    pub bad_checksum_blocks: usize,
+    /// Block sequence numbers missing between 0 and the highest one seen, not counting unreadable blocks.
+    pub missing_blocks: Vec<BlockId>,
+    pub min_block_size: Option<ByteSize>,
+    pub max_block_size: Option<ByteSize>,
+    pub block_size_total: ByteSize,
+}
+
+/// Fetches file info and every block of `file_id` to report on their metadata.
+/// Note the server has no metadata-only call, so this transfers the whole file.
+pub async fn file_details(ctx: &Context, block: ControlBlock, file_id: FileId) -> Result<FileDetails, Box<dyn std::error::Error>> {
+    let info = biz::get_file_info(ctx, file_id).await?;
+    let block_ids = biz::get_block_ids(ctx, block.clone(), file_id).await?.block_ids;
+
+    // at most 16 blocks are fetched at once, and each one's data is dropped right after its checksum
+    let mut fetches = futures_util::stream::iter(block_ids.clone())
+        .map(|block_id| {
+            let ctx = ctx.clone();
+            let block = block.clone();
+            tokio::task::spawn(async move {
+                let report = biz::get_block(&ctx, block, block_id).await.map(|resp| BlockReport {
+                    checksum_ok: checksum(Crc32IsoHdlc, &resp.block_data) as u32 == resp.block_info.block_checksum,
+                    data_size: resp.block_data.len(),
+                    block: resp.block_info,
+                });
+                (block_id, report)
+            })
+        })
+        .buffer_unordered(16);
+
+    let mut blocks = Vec::new();
+    let mut unreadable = Vec::new();
+    while let Some(fetched) = fetches.next().await {
+        match fetched? {
+            (_, Some(report)) => blocks.push(report),
+            (block_id, None) => unreadable.push(block_id),
+        }
+    }
+    blocks.sort_by_key(|report| report.block.block_id);
+
+    Ok(FileDetails { info, block_ids, blocks, unreadable })
+}
+
+impl FileDetails {
+    /// An unreadable block's sequence number is unknown, but the server hands out ids in upload order,
+    /// so a gap in the sequence is put down to the unreadable blocks whose ids lie between the
+    /// readable blocks around it before the rest of it counts as missing.
+    pub fn summary(self) -> Result<(FileSummary, Vec<BlockReport>), Box<dyn std::error::Error>> {
+        let mut missing_blocks = Vec::new();
+        let mut expected = BlockId::default();
+        let mut previous_id = None;
+        for report in &self.blocks {
+            let mut unreadable_between = self
+                .unreadable
+                .iter()
+                .filter(|id| previous_id.is_none_or(|previous| previous < **id) && **id < report.block.id)
+                .count();
+            while expected < report.block.block_id {
+                match unreadable_between {
+                    0 => missing_blocks.push(expected),
+                    _ => unreadable_between -= 1,
+                }
+                expected = expected.next();
+            }
+            expected = report.block.block_id.next();
+            previous_id = Some(report.block.id);
+        }
+
+        let summary = FileSummary {
+            block_count: self.block_ids.len(),
+            unreadable_blocks: self.unreadable.len(),
+            bad_checksum_blocks: self.blocks.iter().filter(|report| !report.checksum_ok).count(),
+            missing_blocks,
+            min_block_size: self.blocks.iter().map(|report| report.block.block_size).min(),
+            max_block_size: self.blocks.iter().map(|report| report.block.block_size).max(),
+            block_size_total: self
+                .blocks
+                .iter()
+                .map(|report| report.block.block_size)
+                .sum::<Option<ByteSize>>()
+                .ok_or_else(|| std::io::Error::other(format!("block sizes of file {} add up to more than 64 bits", self.info.id)))?,
+            info: self.info,
+        };
+
+        Ok((summary, self.blocks))
+    }
+}
+
+/// Counts blocks per size, smallest size first.
+pub fn size_distribution(blocks: &[BlockReport]) -> BTreeMap<ByteSize, usize> {
+    let mut distribution = BTreeMap::new();
+    for report in blocks {
+        *distribution.entry(report.block.block_size).or_insert(0) += 1;
+    }
+    distribution
 }
 
-pub async fn delete_file(block: ControlBlock, file_id: i32) -> Result<(), Box<dyn std::error::Error>> {
-    biz::delete_file(block, file_id).await
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    fn file_info() -> FileInfo {
+        named(7, "a.bin")
+    }
+
+    fn named(id: u32, file_name: &str) -> FileInfo {
+        FileInfo {
+            id: FileId::new(id),
+            file_name: file_name.to_string(),
+            file_size: ByteSize::new(10),
+            file_checksum: 0,
+            file_status: 1,
+            created_at: NaiveDateTime::default(),
+        }
+    }
+
+    fn report(block_id: u64, block_size: u64, checksum_ok: bool) -> BlockReport {
+        BlockReport {
+            block: FileBlock {
+                id: BlockHandle::new(100 + block_id),
+                file_id: FileId::new(7),
+                block_name: format!("a.bin.{}", block_id),
+                block_id: BlockId::new(block_id),
+                block_checksum: 0,
+                block_size: ByteSize::new(block_size),
+                created_at: NaiveDateTime::default(),
+            },
+            data_size: block_size as usize,
+            checksum_ok,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let details = FileDetails {
+            info: file_info(),
+            block_ids: (0..4).map(|id| BlockHandle::new(100 + id)).collect(),
+            blocks: vec![report(0, 4, true), report(1, 4, true), report(2, 2, true), report(3, 0, true)],
+            unreadable: Vec::new(),
+        };
+        let (summary, blocks) = details.summary().unwrap();
+        assert_eq!(summary.block_count, 4);
+        assert!(summary.missing_blocks.is_empty());
+        assert_eq!((summary.unreadable_blocks, summary.bad_checksum_blocks), (0, 0));
+        assert_eq!(summary.min_block_size, Some(ByteSize::new(0)));
+        assert_eq!(summary.max_block_size, Some(ByteSize::new(4)));
+        assert_eq!(summary.block_size_total, ByteSize::new(10));
+        assert_eq!(
+            size_distribution(&blocks),
+            BTreeMap::from([(ByteSize::new(0), 1), (ByteSize::new(2), 1), (ByteSize::new(4), 2)])
+        );
+    }
+
+    #[test]
+    fn test_summary_missing_and_corrupt() {
+        // block 0 could not be read, 3 is gone, 2 fails its checksum
+        let details = FileDetails {
+            info: file_info(),
+            block_ids: [100, 101, 102, 104].map(BlockHandle::new).to_vec(),
+            blocks: vec![report(1, 4, true), report(2, 4, false), report(4, 1, true)],
+            unreadable: vec![BlockHandle::new(100)],
+        };
+        let (summary, _) = details.summary().unwrap();
+        assert_eq!(summary.block_count, 4);
+        // block 0 counts as unreadable only
+        assert_eq!(summary.missing_blocks, vec![BlockId::new(3)]);
+        assert_eq!(summary.unreadable_blocks, 1);
+        assert_eq!(summary.bad_checksum_blocks, 1);
+        assert_eq!(summary.min_block_size, Some(ByteSize::new(1)));
+        assert_eq!(summary.block_size_total, ByteSize::new(9));
+
+        let empty = FileDetails { info: file_info(), block_ids: Vec::new(), blocks: Vec::new(), unreadable: Vec::new() };
+        let (summary, _) = empty.summary().unwrap();
+        assert!(summary.missing_blocks.is_empty());
+        assert_eq!((summary.min_block_size, summary.max_block_size), (None, None));
+        assert_eq!(summary.block_size_total, ByteSize::new(0));
+
+        // sizes from the server are not trusted to add up
+        let huge = FileDetails {
+            info: file_info(),
+            block_ids: [100, 101].map(BlockHandle::new).to_vec(),
+            blocks: vec![report(0, u64::MAX, true), report(1, 1, true)],
+            unreadable: Vec::new(),
+        };
+        assert!(huge.summary().is_err());
+    }
+
+    #[test]
+    fn test_match_name() {
+        let files = || vec![named(1, "report1.txt"), named(2, "report[1].txt"), named(3, "report2.txt"), named(4, "a[b")];
+        let ids = |files: Vec<FileInfo>| files.iter().map(|file| file.id.get()).collect::<Vec<_>>();
+
+        assert_eq!(ids(match_name(files(), "report[1].txt")), vec![2]);
+        assert_eq!(ids(match_name(files(), "report[12].txt")), vec![1, 3]);
+        assert_eq!(ids(match_name(files(), "report?.txt")), vec![1, 3]);
+        assert_eq!(ids(match_name(files(), "report2.txt")), vec![3]);
+        // not a valid glob
+        assert_eq!(ids(match_name(files(), "a[b")), vec![4]);
+        assert!(match_name(files(), "b[").is_empty());
+    }
+
+    #[test]
+    fn test_page_files() {
+        let files = || {
+            vec![
+                FileInfo { file_size: ByteSize::new(30), created_at: NaiveDateTime::default() + chrono::Duration::seconds(2), ..named(1, "b") },
+                FileInfo { file_size: ByteSize::new(10), created_at: NaiveDateTime::default() + chrono::Duration::seconds(3), ..named(2, "a") },
+                FileInfo { file_size: ByteSize::new(10), created_at: NaiveDateTime::default() + chrono::Duration::seconds(1), ..named(3, "c") },
+                FileInfo { file_size: ByteSize::new(20), created_at: NaiveDateTime::default(), ..named(4, "a") },
+            ]
+        };
+        let ids = |files: Vec<FileInfo>| files.iter().map(|file| file.id.get()).collect::<Vec<_>>();
+
+        // ties go to the lower id
+        assert_eq!(ids(page_files(files(), SortKey::Name, false, 0, usize::MAX)), vec![2, 4, 1, 3]);
+        assert_eq!(ids(page_files(files(), SortKey::Size, false, 0, usize::MAX)), vec![2, 3, 4, 1]);
+        assert_eq!(ids(page_files(files(), SortKey::Time, false, 0, usize::MAX)), vec![4, 3, 1, 2]);
+        assert_eq!(ids(page_files(files(), SortKey::Time, true, 0, usize::MAX)), vec![2, 1, 3, 4]);
+
+        // the page is taken after sorting
+        assert_eq!(ids(page_files(files(), SortKey::Name, false, 1, 2)), vec![4, 1]);
+        assert_eq!(ids(page_files(files(), SortKey::Name, true, 3, 10)), vec![2]);
+        assert!(page_files(files(), SortKey::Name, false, 4, 10).is_empty());
+        assert!(page_files(files(), SortKey::Name, false, 0, 0).is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{format_rate, parse_rate};
+
+/// How often running transfers look for limits changed by another process.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token bucket allowing `rate` bytes per second with bursts of up to one second's worth.
+/// Callers reserve their bytes up front and wait off the debt, so concurrent block tasks
+/// queue behind each other instead of all waking at once. A rate of 0 is unlimited.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket { state: Mutex::new(BucketState { rate, tokens: rate as f64, last: Instant::now() }) }
+    }
+
+    /// Applies to bytes taken from now on, including by running transfers.
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate == rate {
+            return;
+        }
+        if state.rate == 0 {
+            state.tokens = rate as f64;
+            state.last = Instant::now();
+        }
+        state.rate = rate;
+        state.tokens = state.tokens.min(rate as f64);
+    }
+
+    /// Reserves `bytes` and returns how long to wait before sending them.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        if state.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = state.rate as f64;
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(rate);
+        state.last = now;
+
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+
+    pub async fn take(&self, bytes: u64) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A global rate applying between `from` and `to` local time, wrapping past midnight if `to` is earlier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    pub rate: u64,
+}
+
+impl ScheduleRule {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+/// The global limit shared by all transfers, saved in `{state_dir}/limit.json` so every client
+/// on the machine, including running ones, follows it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Bytes per second outside the scheduled windows, 0 for unlimited.
+    pub rate: u64,
+    /// The first rule containing the current time wins over `rate`.
+    pub schedule: Vec<ScheduleRule>,
+}
+
+impl Limits {
+    pub fn rate_at(&self, time: NaiveTime) -> u64 {
+        self.schedule
+            .iter()
+            .find(|rule| rule.contains(time))
+            .map_or(self.rate, |rule| rule.rate)
+    }
+}
+
+/// Parses a schedule like `08:00-18:00=2MB/s,18:00-08:00=off`.
+pub fn parse_schedule(s: &str) -> Result<Vec<ScheduleRule>, String> {
+    let parse_time = |time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| format!("illegal time {} in schedule, use HH:MM", time))
+    };
+
+    s.split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (window, rate) = rule.split_once('=').ok_or_else(|| format!("missing =rate in schedule rule {}", rule))?;
+            let (from, to) = window.split_once('-').ok_or_else(|| format!("missing -end in schedule rule {}", rule))?;
+            Ok(ScheduleRule { from: parse_time(from)?, to: parse_time(to)?, rate: parse_rate(rate)? })
+        })
+        .collect()
+}
+
+pub fn format_schedule(schedule: &[ScheduleRule]) -> String {
+    schedule
+        .iter()
+        .map(|rule| format!("{}-{}={}", rule.from.format("%H:%M"), rule.to.format("%H:%M"), format_rate(rule.rate)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The global limit is shared by every transfer in the process, whichever `Client` started it.
+struct Global {
+    /// Where the limit file is, once `init` was called.
+    state_dir: Option<String>,
+    limits: Limits,
+    /// Modification time of the limit file when last read.
+    modified: Option<SystemTime>,
+    checked: Option<Instant>,
+}
+
+static GLOBAL: Mutex<Global> = Mutex::new(Global { state_dir: None, limits: Limits { rate: 0, schedule: Vec::new() }, modified: None, checked: None });
+static GLOBAL_BUCKET: LazyLock<TokenBucket> = LazyLock::new(|| TokenBucket::new(0));
+
+fn limits_path(state_dir: &str) -> String {
+    format!("{}/limit.json", state_dir)
+}
+
+/// Re-reads the limit file if another process changed it since the last look.
+fn reload(global: &mut Global) {
+    let state_dir = match &global.state_dir {
+        Some(state_dir) => state_dir,
+        None => return,
+    };
+    if global.checked.is_some_and(|checked| checked.elapsed() < RELOAD_INTERVAL) {
+        return;
+    }
+    global.checked = Some(Instant::now());
+
+    let path = limits_path(state_dir);
+    let modified = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+    if modified == global.modified {
+        return;
+    }
+    global.modified = modified;
+
+    let limits = match std::fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Limits::default()),
+        Err(e) => Err(e.to_string()),
+    };
+    match limits {
+        Ok(limits) => {
+            tracing::debug!(?limits, "loaded bandwidth limits");
+            global.limits = limits;
+        },
+        Err(e) => tracing::warn!("ignoring bad bandwidth limit file {}: {}", path, e),
+    }
+}
+
+/// Loads the global limit saved in `state_dir`; `rate` from the command line overrides it for this process
+/// until the file changes.
+pub async fn init(state_dir: &str, rate: Option<u64>) {
+    let mut global = GLOBAL.lock().unwrap();
+    global.state_dir = Some(state_dir.to_string());
+    global.checked = None;
+    reload(&mut global);
+    if let Some(rate) = rate {
+        global.limits = Limits { rate, schedule: Vec::new() };
+    }
+}
+
+pub async fn global_limits() -> Limits {
+    let mut global = GLOBAL.lock().unwrap();
+    reload(&mut global);
+    global.limits.clone()
+}
+
+/// Changes the global limit of this and every other running client, and of later ones.
+pub async fn set_global_limits(limits: Limits) -> Result<(), Box<dyn std::error::Error>> {
+    let state_dir = match GLOBAL.lock().unwrap().state_dir.clone() {
+        Some(state_dir) => state_dir,
+        None => return Err(Box::new(std::io::Error::other("global limits are not initialized"))),
+    };
+    let path = limits_path(&state_dir);
+    tokio::fs::create_dir_all(&state_dir).await?;
+    tokio::fs::write(&path, serde_json::to_vec(&limits)?).await?;
+
+    let mut global = GLOBAL.lock().unwrap();
+    global.modified = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+    global.checked = Some(Instant::now());
+    global.limits = limits;
+    Ok(())
+}
+
+/// Rate limit of one transfer, shared by its block tasks: its own limit, if any, and the global one.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    transfer: Arc<TokenBucket>,
+}
+
+impl Default for Throttle {
+    /// Only the global limit.
+    fn default() -> Self {
+        Throttle::new(0)
+    }
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Throttle { transfer: Arc::new(TokenBucket::new(rate)) }
+    }
+
+    /// Changes the limit of the transfer while it runs.
+    pub fn set_rate(&self, rate: u64) {
+        self.transfer.set_rate(rate);
+    }
+
+    /// Waits until `bytes` may be sent or, for downloads, until the next block may be fetched.
+    pub async fn take(&self, bytes: usize) {
+        self.transfer.take(bytes as u64).await;
+
+        let rate = global_limits().await.rate_at(chrono::Local::now().time());
+        GLOBAL_BUCKET.set_rate(rate);
+        GLOBAL_BUCKET.take(bytes as u64).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let start = Instant::now();
+        // the first second's worth goes out at once, the rest waits for the refill
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(500, start), Duration::from_secs(1));
+        assert_eq!(bucket.reserve(500, start + Duration::from_secs(3)), Duration::ZERO);
+
+        bucket.set_rate(0);
+        assert_eq!(bucket.reserve(1 << 40, start), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = parse_schedule("08:00-18:00=2MB/s, 22:00-06:00=off").unwrap();
+        let limits = Limits { rate: 1000, schedule };
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert_eq!(limits.rate_at(time(8, 0)), 2 * 1024 * 1024);
+        assert_eq!(limits.rate_at(time(18, 0)), 1000);
+        assert_eq!(limits.rate_at(time(23, 30)), 0);
+        assert_eq!(limits.rate_at(time(5, 59)), 0);
+        assert_eq!(format_schedule(&limits.schedule), "08:00-18:00=2.0 MiB/s,22:00-06:00=unlimited");
+
+        assert!(parse_schedule("08:00=1MB/s").is_err());
+        assert!(parse_schedule("8am-6pm=1MB/s").is_err());
+    }
+}
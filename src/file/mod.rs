@@ -1,3 +1,7 @@
 pub mod upload;
 pub mod download;
-pub mod info;
\ No newline at end of file
+pub mod info;
+pub mod preflight;
+pub mod clean;
+pub mod limit;
+pub mod transfer;
\ No newline at end of file
@@ -0,0 +1,110 @@
+use std::{ffi::CString, io::ErrorKind};
+
+use uuid::Uuid;
+
+use crate::{control::ControlBlock, core::{biz, client::Context, types::ByteSize}, utils::format_size};
+
+fn preflight_error(message: String) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::other(format!("preflight: {}", message)))
+}
+
+/// Checks that `target_path` is a writable directory with room for a `file_size` download.
+/// Blocks and the joined temp file exist side by side until the blocks are removed,
+/// so twice the file size is needed.
+pub async fn check_download(target_path: &str, file_size: ByteSize) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = match tokio::fs::metadata(target_path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(preflight_error(format!("target directory {} does not exist, create it first", target_path)));
+        },
+        Err(e) => return Err(preflight_error(format!("cannot access {}: {}", target_path, e))),
+    };
+    if !metadata.is_dir() {
+        return Err(preflight_error(format!("{} is not a directory", target_path)));
+    }
+
+    let probe = format!("{}/.{}.probe", target_path, Uuid::new_v4());
+    if let Err(e) = tokio::fs::write(&probe, b"").await {
+        return Err(preflight_error(format!("cannot write to {}: {}", target_path, e)));
+    }
+    let _ = tokio::fs::remove_file(&probe).await;
+
+    let needed = file_size.get().saturating_mul(2);
+    if let Some(available) = available_space(target_path)
+        && available < needed
+    {
+        return Err(preflight_error(format!(
+            "not enough space in {}: need {} (file plus temp blocks), {} available",
+            target_path,
+            format_size(needed),
+            format_size(available)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks that `local_path` is a readable regular file and, with `check_quota` configured,
+/// that it fits the server quota. Returns the file size.
+pub async fn check_upload(ctx: &Context, block: ControlBlock, local_path: &str) -> Result<ByteSize, Box<dyn std::error::Error>> {
+    let metadata = match tokio::fs::metadata(local_path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(preflight_error(format!("{} does not exist", local_path)));
+        },
+        Err(e) => return Err(preflight_error(format!("cannot access {}: {}", local_path, e))),
+    };
+    if !metadata.is_file() {
+        return Err(preflight_error(format!("{} is not a regular file", local_path)));
+    }
+    if let Err(e) = tokio::fs::File::open(local_path).await {
+        return Err(preflight_error(format!("cannot read {}: {}", local_path, e)));
+    }
+
+    let file_size = metadata.len();
+    if ctx.config().check_quota {
+        let quota = match biz::get_quota(ctx, block).await {
+            Ok(quota) => quota,
+            Err(e) => return Err(preflight_error(format!("cannot check the server quota: {}", e))),
+        };
+        let left = quota.limit.get().saturating_sub(quota.used.get());
+        if left < file_size {
+            return Err(preflight_error(format!(
+                "server quota exceeded: {} needs {}, {} of {} left",
+                local_path,
+                format_size(file_size),
+                format_size(left),
+                format_size(quota.limit.get())
+            )));
+        }
+    }
+
+    Ok(ByteSize::new(file_size))
+}
+
+/// Checks that `dir` has room to spool a stream of unknown size; returns how many bytes fit, if known.
+pub fn check_spool(dir: &str) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    match available_space(dir) {
+        Some(0) => Err(preflight_error(format!("no space left in {} to spool the upload", dir))),
+        available => Ok(available),
+    }
+}
+
+/// Free bytes available to unprivileged users on the filesystem holding `path`.
+fn available_space(path: &str) -> Option<u64> {
+    let path = CString::new(path).ok()?;
+    // SAFETY: `statvfs` is plain old data, `path` is NUL-terminated and `stat` outlives the call.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[tokio::test]
+async fn test_check_download() {
+    let dir = std::env::temp_dir().to_string_lossy().to_string();
+    assert!(check_download(&dir, ByteSize::new(0)).await.is_ok());
+    assert!(check_download(&format!("{}/{}", dir, Uuid::new_v4()), ByteSize::new(0)).await.is_err());
+    assert!(check_download(&dir, ByteSize::new(u64::MAX / 2)).await.is_err());
+}
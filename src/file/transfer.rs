@@ -0,0 +1,79 @@
+use std::{
+    fmt,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+};
+
+use crate::file::limit::Throttle;
+
+/// Called with the bytes done so far and the total, 0 if the total isn't known, e.g. for stdin.
+pub type ProgressFn = dyn Fn(u64, u64) + Send + Sync;
+
+/// Counts the bytes a transfer has moved, shared by its block tasks.
+#[derive(Clone, Default)]
+pub struct Progress {
+    callback: Option<Arc<ProgressFn>>,
+    done: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("done", &self.done.load(Ordering::Relaxed))
+            .field("total", &self.total.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Progress {
+    pub fn new(callback: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        Progress { callback: Some(Arc::new(callback)), ..Default::default() }
+    }
+
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Records a finished block; retried blocks are only counted once they succeed.
+    pub fn add(&self, bytes: u64) {
+        let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(callback) = &self.callback {
+            callback(done, self.total.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+}
+
+/// Per-transfer settings of an upload or download. Clones share their state, so a caller can keep
+/// one to change the rate limit of a running transfer.
+#[derive(Debug, Clone, Default)]
+pub struct Transfer {
+    pub throttle: Throttle,
+    pub progress: Progress,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_progress() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let progress = {
+            let seen = seen.clone();
+            Progress::new(move |done, total| seen.lock().unwrap().push((done, total)))
+        };
+        progress.set_total(300);
+
+        let block_task = progress.clone();
+        block_task.add(100);
+        progress.add(200);
+
+        assert_eq!(*seen.lock().unwrap(), vec![(100, 300), (300, 300)]);
+        assert_eq!(progress.done(), 300);
+    }
+}
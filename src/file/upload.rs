@@ -1,93 +1,87 @@
-use std::sync::Arc;
+use std::{os::unix::fs::PermissionsExt as _, sync::Arc};
 
-use crc_fast::{checksum, checksum_file, CrcAlgorithm::Crc32IsoHdlc};
+use crc_fast::{checksum, checksum_file, CrcAlgorithm::Crc32IsoHdlc, Digest};
 use tokio::{
-    io::{self, AsyncReadExt, AsyncSeekExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt as _},
     sync::{Mutex, Semaphore},
 };
+use tracing::Instrument as _;
+use uuid::Uuid;
 
 use crate::{
     control::ControlBlock,
     core::biz,
-    core::{GB, KB, MB},
+    core::{client::Context, types::{BlockId, ByteSize, FileId}, GB, KB, MB},
+    file::{preflight, transfer::Transfer},
+    user::authorization::{self, current},
+    utils::format_size,
 };
 
+/// Block size for streams whose length isn't known up front.
+const STREAM_GRANULARITY: usize = 2 * MB;
+
 pub async fn upload(
+    ctx: &Context,
     block: ControlBlock,
     file_name: &str,
     path: String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let metadata = tokio::fs::metadata(format!("{}/{}", path, file_name)).await?;
-    let file_size = metadata.len() as usize;
+    transfer: Transfer,
+) -> Result<FileId, Box<dyn std::error::Error>> {
+    upload_as(ctx, block, &format!("{}/{}", path, file_name), file_name, transfer).await
+}
+
+/// Uploads the local file at `local_path` under `file_name` and returns the new file id.
+#[tracing::instrument(name = "upload", skip(ctx, block, transfer), fields(file_id))]
+pub async fn upload_as(
+    ctx: &Context,
+    block: ControlBlock,
+    local_path: &str,
+    file_name: &str,
+    transfer: Transfer,
+) -> Result<FileId, Box<dyn std::error::Error>> {
+    let _keep_alive = authorization::keep_alive(ctx, &block);
+    let file_size = preflight::check_upload(ctx, current(ctx, &block), local_path).await?;
+    transfer.progress.set_total(file_size.get());
     let granularity = calcu_granularity(file_size);
 
     let semaphore = Arc::new(Semaphore::new(8));
     let mut handles = Vec::new();
-    let file = tokio::fs::File::open(format!("{}/{}", path, file_name)).await?;
+    let file = tokio::fs::File::open(local_path).await?;
     let mut buffer = Vec::with_capacity(granularity);
-    let mut position = 0;
-
-    let block_clone = block.clone();
-    let file_id = biz::presend(block_clone, file_name, file_size).await?;
 
-    let mut block_id = 0;
+    let file_id = biz::presend(ctx, current(ctx, &block), file_name, file_size).await?;
+    tracing::Span::current().record("file_id", tracing::field::display(file_id));
 
     let mutex_flag = Arc::new(Mutex::new(true));
 
-    loop {
+    for (block_id, offset, len) in plan_blocks(file_size, granularity) {
         let mut file_clone = file.try_clone().await?;
-        file_clone.seek(io::SeekFrom::Start(position)).await?;
+        file_clone.seek(io::SeekFrom::Start(offset)).await?;
 
         buffer.clear();
 
         let bytes_read = file_clone
-            .take(granularity as u64)
+            .take(len as u64)
             .read_to_end(&mut buffer)
             .await?;
 
-        if bytes_read == 0 {
-            break;
+        if bytes_read < len {
+            return Err(Box::new(std::io::Error::other(format!("{} shrank during the upload", local_path))));
         }
 
-        position += bytes_read as u64;
-
-        let block_checksum = checksum(Crc32IsoHdlc, &buffer);
-
         let semaphore_clone = Arc::clone(&semaphore);
+        let ctx = ctx.clone();
         let block_clone = block.clone();
 
         let mutex_flag = mutex_flag.clone();
         let data_use =  buffer.clone();
+        let transfer = transfer.clone();
         
         let handle = tokio::task::spawn(async move {
             let _permit = semaphore_clone.acquire().await.unwrap();
-            let mut success = false;
-            for _ in 0..3 {
-                if !*mutex_flag.lock().await {
-                    break;
-                }
-
-                let block_use = block_clone.clone();
-                let data_use =  data_use.clone();
-                let rst = biz::send(
-                    block_use,
-                    file_id,
-                    block_id,
-                    block_checksum as u32,
-                    data_use,
-                )
-                .await;
-                if let Ok(_) = rst {
-                    success = true;
-                    break;
-                }
-            }
-            if !success {
-                *mutex_flag.lock().await = false;
-            }
-        });
-
-        block_id += 1;
+            send_block(&ctx, block_clone, file_id, block_id, data_use, mutex_flag, transfer).await;
+        }.in_current_span());
+
         handles.push(handle);
     }
 
@@ -103,23 +97,271 @@ pub async fn upload(
         )));
     }
 
-    let crc32 = checksum_file(Crc32IsoHdlc, &format!("{}/{}", path, file_name), None)?;
+    let crc32 = checksum_file(Crc32IsoHdlc, local_path, None)?;
+
+    biz::finish(ctx, current(ctx, &block), file_id, crc32 as u32).await?;
+
+    Ok(file_id)
+}
+
+/// Sends one block with up to three attempts, clearing `mutex_flag` if all of them fail.
+/// The token is refreshed once after the first failure in case it expired mid-transfer.
+/// Every attempt waits for the bandwidth limit.
+#[tracing::instrument(name = "block", skip(ctx, block, file_id, block_id, data, mutex_flag, transfer), fields(file_id = %file_id, block_id = %block_id))]
+async fn send_block(
+    ctx: &Context,
+    block: ControlBlock,
+    file_id: FileId,
+    block_id: BlockId,
+    data: Vec<u8>,
+    mutex_flag: Arc<Mutex<bool>>,
+    transfer: Transfer,
+) {
+    let block_checksum = checksum(Crc32IsoHdlc, &data) as u32;
+    let mut success = false;
+    let mut reauthed = false;
+    for attempt in 1..=3 {
+        if !*mutex_flag.lock().await {
+            break;
+        }
+
+        transfer.throttle.take(data.len()).await;
+
+        let block_use = current(ctx, &block);
+        let data_use = data.clone();
+        let sent = biz::send(ctx, block_use.clone(), file_id, block_id, block_checksum, data_use)
+            .instrument(tracing::debug_span!("attempt", attempt))
+            .await
+            .is_ok();
+        if sent {
+            transfer.progress.add(data.len() as u64);
+            success = true;
+            break;
+        }
+        tracing::warn!(attempt, "send block failed");
+
+        // the server doesn't say why a send failed, so try a fresh token once
+        if !reauthed {
+            reauthed = true;
+            let _ = authorization::reauth(ctx, &block_use).await;
+        }
+    }
+    if !success {
+        tracing::error!("giving up on block");
+        *mutex_flag.lock().await = false;
+    }
+}
+
+/// Uploads everything read from `reader` under `file_name`, e.g. stdin.
+/// The stream is sent as it is read when the server accepts a presend without a size,
+/// otherwise it is spooled to a temp file first.
+pub async fn upload_stream<R>(
+    ctx: &Context,
+    block: ControlBlock,
+    reader: &mut R,
+    file_name: &str,
+    transfer: Transfer,
+) -> Result<FileId, Box<dyn std::error::Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    if ctx.config().deferred_presend {
+        return upload_unsized(ctx, block, reader, file_name, transfer).await;
+    }
+
+    // the spool holds whatever was piped in, so keep it where only we can read it
+    let spool_dir = format!("{}/spool", ctx.config().state_dir);
+    tokio::fs::create_dir_all(&spool_dir).await?;
+    tokio::fs::set_permissions(&spool_dir, std::fs::Permissions::from_mode(0o700)).await?;
+    let available = preflight::check_spool(&spool_dir)?;
+
+    let spool_path = format!("{}/{}", spool_dir, Uuid::new_v4());
+    let rst = async {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true).mode(0o600);
+        let mut spool = options.open(&spool_path).await?;
+        let limit = available.unwrap_or(u64::MAX);
+        let copied = io::copy(&mut (&mut *reader).take(limit), &mut spool).await?;
+        if copied == limit && reader.read(&mut [0]).await? > 0 {
+            return Err(Box::new(std::io::Error::other(format!(
+                "not enough space in {} to spool the upload, {} available",
+                spool_dir,
+                format_size(limit)
+            ))) as Box<dyn std::error::Error>);
+        }
+        spool.flush().await?;
+        drop(spool);
+        upload_as(ctx, block, &spool_path, file_name, transfer).await
+    }
+    .await;
+
+    let _ = tokio::fs::remove_file(&spool_path).await;
+    rst
+}
+
+#[tracing::instrument(name = "upload", skip(ctx, block, reader, transfer), fields(file_id))]
+async fn upload_unsized<R>(
+    ctx: &Context,
+    block: ControlBlock,
+    reader: &mut R,
+    file_name: &str,
+    transfer: Transfer,
+) -> Result<FileId, Box<dyn std::error::Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    let _keep_alive = authorization::keep_alive(ctx, &block);
+    let file_id = biz::presend(ctx, current(ctx, &block), file_name, ByteSize::new(0)).await?;
+    tracing::Span::current().record("file_id", tracing::field::display(file_id));
+
+    let semaphore = Arc::new(Semaphore::new(8));
+    let mutex_flag = Arc::new(Mutex::new(true));
+    let mut handles = Vec::new();
+    let mut blocks = StreamBlocks::new(reader, STREAM_GRANULARITY);
+
+    loop {
+        // take the permit before reading so at most 8 blocks are held in memory
+        let permit = semaphore.clone().acquire_owned().await?;
+        let Some((block_id, buffer)) = blocks.next().await? else {
+            break;
+        };
+
+        let ctx = ctx.clone();
+        let block_clone = block.clone();
+        let mutex_flag = mutex_flag.clone();
+        let transfer = transfer.clone();
+        handles.push(tokio::task::spawn(async move {
+            let _permit = permit;
+            send_block(&ctx, block_clone, file_id, block_id, buffer, mutex_flag, transfer).await;
+        }.in_current_span()));
+    }
+
+    for handle in handles {
+        handle.await?;
+    }
+
+    if !*mutex_flag.lock().await {
+        return Err(Box::new(std::io::Error::other("Upload failed")));
+    }
+
+    biz::finish(ctx, current(ctx, &block), file_id, blocks.file_checksum()).await?;
+
+    Ok(file_id)
+}
+
+/// Cuts a stream of unknown length into blocks numbered from 0, keeping the checksum of the whole stream.
+struct StreamBlocks<'a, R> {
+    reader: &'a mut R,
+    granularity: usize,
+    next_id: BlockId,
+    digest: Digest,
+}
+
+impl<'a, R: AsyncRead + Unpin> StreamBlocks<'a, R> {
+    fn new(reader: &'a mut R, granularity: usize) -> Self {
+        StreamBlocks { reader, granularity, next_id: BlockId::default(), digest: Digest::new(Crc32IsoHdlc) }
+    }
+
+    /// The next block, `None` at the end of the stream.
+    async fn next(&mut self) -> io::Result<Option<(BlockId, Vec<u8>)>> {
+        let mut buffer = Vec::with_capacity(self.granularity);
+        let bytes_read = (&mut *self.reader)
+            .take(self.granularity as u64)
+            .read_to_end(&mut buffer)
+            .await?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+
+        self.digest.update(&buffer);
+        let block_id = self.next_id;
+        self.next_id = block_id.next();
+        Ok(Some((block_id, buffer)))
+    }
 
-    biz::finish(block, file_id, crc32 as u32).await
+    fn file_checksum(&self) -> u32 {
+        self.digest.finalize() as u32
+    }
+}
+
+/// Splits `file_size` bytes into blocks of `granularity`, the last one possibly shorter:
+/// each block's id, offset and length.
+fn plan_blocks(file_size: ByteSize, granularity: usize) -> impl Iterator<Item = (BlockId, u64, usize)> {
+    let granularity = granularity as u64;
+    (0..file_size.get().div_ceil(granularity)).map(move |n| {
+        let offset = n * granularity;
+        (BlockId::new(n), offset, (file_size.get() - offset).min(granularity) as usize)
+    })
 }
 
-fn calcu_granularity(size: usize) -> usize {
-    if size < 16 * MB {
+fn calcu_granularity(size: ByteSize) -> usize {
+    let size = size.get();
+    if size < 16 * MB as u64 {
         return 128 * KB;
     }
-    if size < 64 * MB {
+    if size < 64 * MB as u64 {
         return 512 * KB;
     }
-    if size < 128 * MB {
+    if size < 128 * MB as u64 {
         return 2 * MB;
     }
-    if size < 1 * GB {
+    if size < 1 * GB as u64 {
         return 8 * MB;
     }
     return 16 * MB;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_granularity_over_4gib() {
+        let five_gib = ByteSize::new(5 * GB as u64);
+        assert_eq!(calcu_granularity(five_gib), 16 * MB);
+        // a 4 GiB + 1 KiB file must not wrap around to a tiny size on 32-bit targets
+        assert_eq!(calcu_granularity(ByteSize::new(4 * GB as u64 + KB as u64)), 16 * MB);
+        assert_eq!(calcu_granularity(ByteSize::new(KB as u64)), 128 * KB);
+        assert_eq!(five_gib.get().div_ceil(calcu_granularity(five_gib) as u64), 320);
+    }
+
+    #[test]
+    fn test_plan_blocks_over_4gib() {
+        let size = ByteSize::new(u32::MAX as u64 + 1 + KB as u64);
+        let granularity = calcu_granularity(size);
+        let blocks = plan_blocks(size, granularity).collect::<Vec<_>>();
+        assert_eq!(blocks.len(), 257);
+        assert_eq!(blocks.last(), Some(&(BlockId::new(256), 4 * GB as u64, KB)));
+
+        // joined back in id order the blocks cover the file exactly once
+        let mut end = 0;
+        for (n, (block_id, offset, len)) in blocks.into_iter().enumerate() {
+            assert_eq!(block_id, BlockId::new(n as u64));
+            assert_eq!(offset, end);
+            end += len as u64;
+        }
+        assert_eq!(end, size.get());
+        assert_eq!(plan_blocks(ByteSize::new(0), granularity).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_blocks() {
+        let data = (0..10u8).collect::<Vec<_>>();
+        let mut reader = data.as_slice();
+        let mut blocks = StreamBlocks::new(&mut reader, 4);
+
+        let mut sizes = Vec::new();
+        while let Some((block_id, buffer)) = blocks.next().await.unwrap() {
+            assert_eq!(block_id, BlockId::new(sizes.len() as u64));
+            sizes.push(buffer.len());
+        }
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert_eq!(blocks.file_checksum(), checksum(Crc32IsoHdlc, &data) as u32);
+
+        // an empty stream is an empty file
+        let mut reader: &[u8] = &[];
+        let mut blocks = StreamBlocks::new(&mut reader, 4);
+        assert!(blocks.next().await.unwrap().is_none());
+        assert_eq!(blocks.file_checksum(), checksum(Crc32IsoHdlc, b"") as u32);
+    }
+}
@@ -0,0 +1,14 @@
+//! Client for the SSL file server: login sessions, listing, chunked uploads and downloads
+//! over mutually verified TLS. `Client` is the entry point; the modules hold the pieces it is
+//! built from for callers that need more control.
+
+pub mod control;
+pub mod core;
+pub mod file;
+pub mod user;
+pub mod utils;
+mod api;
+
+pub use api::Client;
+pub use core::{client::ClientConfig, types::{BlockHandle, BlockId, ByteSize, FileId}};
+pub use file::transfer::{Progress, Transfer};
@@ -1,24 +1,218 @@
-use crate::core::client::ClientConfig;
+use client::{core::{self, endpoint::Endpoint, tls::ClientIdentity}, file::{self, download::OverwritePolicy, info::Disambiguation}, utils, Client, ClientConfig};
 
-mod core;
-mod utils;
-mod control;
-mod file;
-mod user;
 mod terminal;
 
+/// Removes `flag value` from the command line, exiting if the value is missing.
+fn take_flag(args: &mut Vec<String>, flag: &str, usage: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    args.remove(pos);
+    if pos >= args.len() {
+        eprintln!("{} needs {}", flag, usage);
+        std::process::exit(2);
+    }
+    Some(args.remove(pos))
+}
+
+/// Removes every `flag value` from the command line.
+fn take_flags(args: &mut Vec<String>, flag: &str, usage: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    while let Some(value) = take_flag(args, flag, usage) {
+        values.push(value);
+    }
+    values
+}
+
+/// Removes a `flag` without value from the command line, returning whether it was given.
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
 #[tokio::main]
 async fn main() -> ! {
 
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let output = take_flag(&mut args, "--output", "one of table, json, csv");
+    let profile = take_flag(&mut args, "--profile", "a profile name");
+    let session_key_file = take_flag(&mut args, "--session-key", "a key file");
+    let log_filter = take_flag(&mut args, "--log", "a filter like info or warn,client::file=debug");
+    let log_dir = take_flag(&mut args, "--log-dir", "a directory");
+    let client_cert = take_flag(&mut args, "--client-cert", "a PEM certificate file");
+    let client_key = take_flag(&mut args, "--client-key", "a PEM key file");
+    let client_p12 = take_flag(&mut args, "--client-p12", "a PKCS#12 file");
+    let endpoints = take_flags(&mut args, "--endpoint", "a server like host:port or [::1]:port");
+    let sni = take_flag(&mut args, "--sni", "the server's host name");
+    let proxy = take_flag(&mut args, "--proxy", "a proxy like socks5://host:1080, http://host:3128 or none");
+    let no_proxy = take_flag(&mut args, "--no-proxy", "a list of hosts like localhost,.example.com");
+    let ca_file = take_flag(&mut args, "--ca-file", "a PEM CA file");
+    let ca_dir = take_flag(&mut args, "--ca-dir", "a directory of PEM CA files");
+    let pins = take_flags(&mut args, "--pin", "a key fingerprint like sha256/<base64>");
+    let tls_min = take_flag(&mut args, "--tls-min", "1.2 or 1.3");
+    let tls_max = take_flag(&mut args, "--tls-max", "1.2 or 1.3");
+    let cipher_list = take_flag(&mut args, "--ciphers", "an openssl cipher list");
+    let ciphersuites = take_flag(&mut args, "--ciphersuites", "an openssl TLS 1.3 ciphersuite list");
+    let global_limit = take_flag(&mut args, "--global-limit", "a rate like 5MB/s");
+    let crl_files = take_flags(&mut args, "--crl", "a CRL file");
+    let system_trust = take_switch(&mut args, "--system-trust");
+    let trust_on_first_use = take_switch(&mut args, "--tofu");
+    let deferred_presend = take_switch(&mut args, "--deferred-presend");
+    let check_quota = take_switch(&mut args, "--check-quota");
+    let debug_unsafe = take_switch(&mut args, "--debug-unsafe");
+    let debug = take_switch(&mut args, "--debug");
+
+    // an explicit filter wins over the environment, which wins over the debug switches
+    let log_filter = log_filter
+        .or_else(|| std::env::var(core::log::LOG_ENV).ok())
+        .unwrap_or_else(|| match (debug_unsafe, debug) {
+            (true, _) => "trace".to_string(),
+            (false, true) => "debug".to_string(),
+            _ => core::log::DEFAULT_LOG_FILTER.to_string(),
+        });
+    if let Err(e) = core::log::init_logging(&log_filter, log_dir.as_deref()) {
+        eprintln!("bad logging options: {}", e);
+        std::process::exit(2);
+    }
+
+    if let Some(output) = output {
+        match output.parse() {
+            Ok(format) => terminal::output::set_output_format(format),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            },
+        }
+    }
+
+    // the profile names the session file
+    let profile = profile.unwrap_or_else(|| "default".to_string());
+    if let Err(e) = file::download::sanitize_file_name(&profile) {
+        eprintln!("bad profile name: {}", e);
+        std::process::exit(2);
+    }
+
+    let state_dir = core::client::default_state_dir();
+
+    // endpoints on the command line win over the profile's failover list
+    let endpoints = match endpoints.iter().map(|endpoint| endpoint.parse()).collect::<Result<Vec<_>, _>>() {
+        Ok(endpoints) if !endpoints.is_empty() => endpoints,
+        Ok(_) => core::endpoint::profile_endpoints(&state_dir, &profile).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    };
+    // the built-in server is dialed by IP but its certificate is issued to localhost
+    let (endpoints, domain) = if endpoints.is_empty() {
+        let local = Endpoint { host: "127.0.0.1".to_string(), port: core::endpoint::DEFAULT_PORT };
+        (vec![local], sni.or_else(|| Some("localhost".to_string())))
+    } else {
+        (endpoints, sni)
+    };
+
+    // the command line wins over HTTPS_PROXY, ALL_PROXY and NO_PROXY
+    let mut proxies = core::proxy::ProxyConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("bad proxy environment: {}", e);
+        std::process::exit(2);
+    });
+    match proxy.as_deref() {
+        Some("none") => proxies.proxy = None,
+        Some(proxy) => proxies.proxy = Some(proxy.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        })),
+        None => {},
+    }
+    if let Some(no_proxy) = no_proxy {
+        proxies.no_proxy = core::proxy::parse_no_proxy(&no_proxy);
+    }
+
+    // kept out of the command line so it doesn't show up in the process list
+    let passphrase = std::env::var("CLIENT_KEY_PASSPHRASE").ok();
+    let client_identity = match (client_cert, client_key, client_p12) {
+        (None, None, None) => None,
+        (Some(cert_file), Some(key_file), None) => Some(ClientIdentity::Pem { cert_file, key_file, passphrase }),
+        (None, None, Some(file)) => Some(ClientIdentity::Pkcs12 { file, passphrase }),
+        _ => {
+            eprintln!("use either --client-cert with --client-key, or --client-p12");
+            std::process::exit(2);
+        },
+    };
+
+    let pins = match pins.iter().map(|pin| core::tls::normalize_pin(pin)).collect::<Result<Vec<_>, _>>() {
+        Ok(pins) => pins,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    };
+
+    let (tls_min_version, tls_max_version) = match (
+        tls_min.map(|version| core::tls::parse_tls_version(&version)).transpose(),
+        tls_max.map(|version| core::tls::parse_tls_version(&version)).transpose(),
+    ) {
+        (Ok(min), Ok(max)) => (min, max),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    };
+
+    // the bundled cert.pem stays the default unless another trust source is chosen
+    let cert_file = match ca_file {
+        Some(ca_file) => ca_file,
+        None if system_trust || ca_dir.is_some() => String::new(),
+        None => "cert.pem".to_string(),
+    };
+
     let config = ClientConfig {
-        cert_file: "cert.pem".to_string(),
-        addr: "127.0.0.1".to_string(),
-        port: 17878,
-        domain: "localhost".to_string(),
-        debug: false
+        cert_file,
+        system_trust,
+        ca_dir,
+        pins,
+        trust_on_first_use,
+        endpoints,
+        domain,
+        proxies,
+        tls_min_version,
+        tls_max_version,
+        cipher_list,
+        ciphersuites,
+        crl_files,
+        client_identity,
+        debug_unsafe,
+        disambiguation: Disambiguation::Error,
+        deferred_presend,
+        check_quota,
+        overwrite: OverwritePolicy::Fail,
+        state_dir,
+        profile,
+        session_key_file,
     };
 
-    core::client::init_config(config).await;
+    let client = Client::new(config);
+
+    match global_limit.map(|rate| utils::parse_rate(&rate)).transpose() {
+        Ok(rate) => file::limit::init(&client.config().state_dir, rate).await,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    }
+
+    let report = file::clean::clean_crashed(&client.config().state_dir).await;
+    if !report.removed_files.is_empty() {
+        eprintln!("removed {} temp files left by a crashed download", report.removed_files.len());
+    }
+
+    // `client <command> [args]` runs a single command, e.g. in a pipe
+    if !args.is_empty() {
+        let cmd = args.remove(0);
+        terminal::run_once(client, cmd, args).await
+    }
 
-    terminal::terminal().await
-}
\ No newline at end of file
+    terminal::terminal(client).await
+}
@@ -0,0 +1,291 @@
+/// Splits a command line into words like a POSIX shell: single quotes keep everything literal,
+/// double quotes still expand `$VAR` and take `\"`, `\\` and `\$` escapes, a backslash outside
+/// quotes escapes any character, and `~` at the start of a word is the home directory.
+/// Unset variables expand to nothing, and expansions are never split into several words.
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    tokenize_with(line, |name| std::env::var(name).ok())
+}
+
+fn tokenize_with(line: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // quotes make a word even if it ends up empty, e.g. `''`
+    let mut in_word = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated single quote".to_string()),
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            },
+                            None => return Err("unterminated double quote".to_string()),
+                        },
+                        Some('$') => expand_var(&mut chars, &mut word, &lookup)?,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated double quote".to_string()),
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some(c) => {
+                    in_word = true;
+                    word.push(c);
+                },
+                None => return Err("nothing to escape after trailing \\".to_string()),
+            },
+            '$' => {
+                in_word = true;
+                expand_var(&mut chars, &mut word, &lookup)?;
+            },
+            '~' if !in_word && chars.peek().is_none_or(|next| next.is_whitespace() || *next == '/') => {
+                in_word = true;
+                match lookup("HOME") {
+                    Some(home) => word.push_str(&home),
+                    None => word.push('~'),
+                }
+            },
+            c => {
+                in_word = true;
+                word.push(c);
+            },
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Expands `$NAME` or `${NAME}` after the `$`; a `$` not followed by a name stays as it is.
+fn expand_var(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    word: &mut String,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    let mut name = String::new();
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => name.push(c),
+                None => return Err("unterminated ${".to_string()),
+            }
+        }
+    } else {
+        while let Some(&c) = chars.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '_') {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name.is_empty() {
+            word.push('$');
+            return Ok(());
+        }
+    }
+    word.push_str(&lookup(&name).unwrap_or_default());
+    Ok(())
+}
+
+/// The arguments a command takes. A positional name ending in `?` is optional and one ending in
+/// `...` takes any number of values; flags are `--name value`, `--name=value` or switches.
+/// Everything after `--` is positional, so file names may start with dashes.
+#[derive(Debug, Clone, Copy)]
+pub struct Schema {
+    pub positionals: &'static [&'static str],
+    /// Flags taking a value.
+    pub options: &'static [&'static str],
+    /// Flags without a value.
+    pub switches: &'static [&'static str],
+}
+
+impl Schema {
+    pub const fn new(positionals: &'static [&'static str], options: &'static [&'static str], switches: &'static [&'static str]) -> Self {
+        Schema { positionals, options, switches }
+    }
+
+    pub fn parse(&self, words: Vec<String>) -> Result<Args, String> {
+        let mut args = Args::default();
+        let mut values = Vec::new();
+
+        let mut iter = words.into_iter();
+        while let Some(word) = iter.next() {
+            if word == "--" {
+                values.extend(iter.by_ref());
+                break;
+            }
+            if !word.starts_with("--") {
+                values.push(word);
+                continue;
+            }
+
+            let (flag, inline) = match word.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (word.as_str(), None),
+            };
+            if let Some(&option) = self.options.iter().find(|option| **option == flag) {
+                match inline.or_else(|| iter.next()) {
+                    Some(value) => args.options.push((option, value)),
+                    None => return Err(format!("{} needs a value", option)),
+                }
+            } else if let Some(&switch) = self.switches.iter().find(|switch| **switch == flag) {
+                if inline.is_some() {
+                    return Err(format!("{} takes no value", switch));
+                }
+                args.switches.push(switch);
+            } else {
+                return Err(format!("unknown flag {}", flag));
+            }
+        }
+
+        let mut values = values.into_iter();
+        for spec in self.positionals {
+            if let Some(name) = spec.strip_suffix("...") {
+                args.positionals.extend(values.by_ref().map(|value| (name, value)));
+            } else if let Some(name) = spec.strip_suffix('?') {
+                if let Some(value) = values.next() {
+                    args.positionals.push((name, value));
+                }
+            } else {
+                match values.next() {
+                    Some(value) => args.positionals.push((spec, value)),
+                    None => return Err(format!("missing {}", spec)),
+                }
+            }
+        }
+        if let Some(value) = values.next() {
+            return Err(format!("unexpected argument {}", value));
+        }
+
+        Ok(args)
+    }
+}
+
+/// Arguments parsed by a `Schema`, looked up by name.
+#[derive(Debug, Default)]
+pub struct Args {
+    positionals: Vec<(&'static str, String)>,
+    options: Vec<(&'static str, String)>,
+    switches: Vec<&'static str>,
+}
+
+impl Args {
+    /// A required positional; the schema made sure it is there.
+    pub fn arg(&self, name: &str) -> &str {
+        self.opt_arg(name).unwrap_or_else(|| panic!("{} is not a required argument", name))
+    }
+
+    pub fn opt_arg(&self, name: &str) -> Option<&str> {
+        self.positionals.iter().find(|(n, _)| *n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// All values of a `...` positional.
+    pub fn args(&self, name: &str) -> Vec<String> {
+        self.positionals.iter().filter(|(n, _)| *n == name).map(|(_, value)| value.clone()).collect()
+    }
+
+    /// The value of `flag`, the last one if given more than once.
+    pub fn value(&self, flag: &str) -> Option<&str> {
+        self.options.iter().rev().find(|(f, _)| *f == flag).map(|(_, value)| value.as_str())
+    }
+
+    /// The value of `flag` run through `parse`, with the flag and value in the error.
+    pub fn value_as<T, E: std::fmt::Display>(&self, flag: &str, parse: impl FnOnce(&str) -> Result<T, E>) -> Result<Option<T>, String> {
+        self.value(flag)
+            .map(|value| parse(value).map_err(|e| format!("{} {}: {}", flag, value, e)))
+            .transpose()
+    }
+
+    pub fn has(&self, switch: &str) -> bool {
+        self.switches.contains(&switch)
+    }
+
+    /// Which of the mutually exclusive `switches` was given last.
+    pub fn last_of(&self, switches: &[&'static str]) -> Option<&'static str> {
+        self.switches.iter().rev().find(|switch| switches.contains(switch)).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/me".to_string()),
+            "DIR" => Some("my files".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let words = |line| tokenize_with(line, lookup).unwrap();
+        assert_eq!(words("  upload  a.txt   /tmp "), vec!["upload", "a.txt", "/tmp"]);
+        assert_eq!(words(r#"upload "my file.txt" 'dir with $DIR'"#), vec!["upload", "my file.txt", "dir with $DIR"]);
+        assert_eq!(words(r"upload my\ file.txt \~"), vec!["upload", "my file.txt", "~"]);
+        assert_eq!(words(r#"say "a \"b\" \$c \n" '' """#), vec!["say", r#"a "b" $c \n"#, "", ""]);
+        assert_eq!(words("ls ~ ~/docs a~ ~other"), vec!["ls", "/home/me", "/home/me/docs", "a~", "~other"]);
+        assert_eq!(words(r#"cd $DIR "$DIR/x" ${DIR}y $UNSET. $ 5$"#), vec!["cd", "my files", "my files/x", "my filesy", ".", "$", "5$"]);
+
+        assert!(tokenize_with("say 'open", lookup).is_err());
+        assert!(tokenize_with("say \"open", lookup).is_err());
+        assert!(tokenize_with("say trailing\\", lookup).is_err());
+        assert!(tokenize_with("say ${open", lookup).is_err());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = Schema::new(&["file", "path?"], &["--limit", "--pick"], &["--overwrite", "--rename"]);
+        let words = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+
+        let args = schema.parse(words("a --limit 5MB/s --rename b --pick=newest --overwrite")).unwrap();
+        assert_eq!(args.arg("file"), "a");
+        assert_eq!(args.opt_arg("path"), Some("b"));
+        assert_eq!(args.value("--limit"), Some("5MB/s"));
+        assert_eq!(args.value("--pick"), Some("newest"));
+        assert_eq!(args.last_of(&["--overwrite", "--rename"]), Some("--overwrite"));
+        assert_eq!(args.value_as("--limit", |_| "bad".parse::<u32>()).unwrap_err(), "--limit 5MB/s: invalid digit found in string");
+
+        let args = schema.parse(words("a -- --rename")).unwrap();
+        assert_eq!(args.opt_arg("path"), Some("--rename"));
+        assert!(!args.has("--rename"));
+        assert_eq!(schema.parse(words("-")).unwrap().opt_arg("path"), None);
+
+        assert_eq!(schema.parse(Vec::new()).unwrap_err(), "missing file");
+        assert_eq!(schema.parse(words("a b c")).unwrap_err(), "unexpected argument c");
+        assert_eq!(schema.parse(words("a --limit")).unwrap_err(), "--limit needs a value");
+        assert_eq!(schema.parse(words("a --rename=yes")).unwrap_err(), "--rename takes no value");
+        assert_eq!(schema.parse(words("a --force")).unwrap_err(), "unknown flag --force");
+
+        let schema = Schema::new(&["dir..."], &[], &[]);
+        assert_eq!(schema.parse(words("x y")).unwrap().args("dir"), vec!["x", "y"]);
+        assert!(schema.parse(Vec::new()).unwrap().args("dir").is_empty());
+    }
+}
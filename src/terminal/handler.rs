@@ -1,193 +1,770 @@
+use std::{io::IsTerminal as _, time::Duration};
+
+use chrono::TimeZone as _;
+use serde::Serialize;
 use tabled::{Table, Tabled};
+use zeroize::Zeroizing;
+
+use client::{core::{biz::FileInfo, tls}, file::{self, download::OverwritePolicy, info::Disambiguation, limit::{self, Throttle}}, utils::{format_duration, format_rate, format_size, parse_duration, parse_rate}, BlockHandle, BlockId, ByteSize, Client, FileId, Progress, Transfer};
 
-use crate::{control::ControlBlock, file, terminal::{async_print, help}, user};
+use crate::terminal::{args::Args, async_print, help, read_answer, read_password, output::{self, emit, is_structured, report, report_error, CommandRecord}, page, sync::ConflictPolicy};
 
-pub async fn login(block: &mut ControlBlock, args: Option<Vec<String>>) -> Option<String> {
-    let (user_name, passwd) = match args {
-        Some(args) => {
-            if args.len() < 2 {
-                help(Some(vec!["login".to_string()])).await;
+/// Environment variable holding the password for scripted logins.
+const PASSWORD_ENV: &str = "CLIENT_PASSWORD";
+
+/// Gets the password of `[user_name] [--password-file f]` from the file, the environment
+/// or a no-echo prompt, in that order. A password given as a second argument is refused
+/// without echoing it, as it would end up in the shell history.
+async fn take_credentials(command: &str, args: Args, confirm: bool) -> Option<(String, Zeroizing<String>)> {
+    let user_name = args.arg("user_name").to_string();
+    if args.opt_arg("password").is_some() {
+        report_error(command, format!("passwords are no longer accepted as an argument, use --password-file, ${} or the prompt", PASSWORD_ENV)).await;
+        return None;
+    }
+    let mut password = None;
+
+    if let Some(path) = args.value("--password-file") {
+        let mut contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => Zeroizing::new(contents),
+            Err(e) => {
+                report_error(command, format!("read password file {} failed: {:?}", path, e)).await;
                 return None;
-            }
-            (args[0].to_owned(), args[1].to_owned())
-        },
+            },
+        };
+        // only the first line is the password, the rest may be a trailing newline
+        let len = contents.lines().next().map_or(0, str::len);
+        contents.truncate(len);
+        password = Some(contents);
+    }
+
+    if password.is_none() {
+        password = std::env::var(PASSWORD_ENV).ok().map(Zeroizing::new);
+    }
+
+    if let Some(password) = password {
+        return Some((user_name, password));
+    }
+
+    let password = match read_password("password: ".to_string()).await {
+        Some(password) => password,
         None => {
-            help(Some(vec!["login".to_string()])).await;
+            report_error(command, format!("no terminal to ask for a password, use --password-file or ${}", PASSWORD_ENV)).await;
             return None;
         },
     };
+    if confirm && read_password("repeat password: ".to_string()).await.as_deref() != Some(&*password) {
+        report_error(command, "passwords do not match".to_string()).await;
+        return None;
+    }
+
+    Some((user_name, password))
+}
+
+pub async fn login(client: &Client, args: Args) {
+    let (user_name, passwd) = match take_credentials("login", args, false).await {
+        Some(credentials) => credentials,
+        None => return,
+    };
+
+    match client.login(&user_name, &passwd).await {
+        Ok(_) => emit(&[CommandRecord { command: "login".to_string(), success: true, ..Default::default() }]).await,
+        Err(e) => report_error("login", format!("login failed: {:?}", e)).await,
+    }
+}
+
+pub async fn register(client: &Client, args: Args) {
+    let (user_name, passwd) = match take_credentials("register", args, true).await {
+        Some(credentials) => credentials,
+        None => return,
+    };
+
+    match client.register(&user_name, &passwd).await {
+        Ok(_) => emit(&[CommandRecord { command: "register".to_string(), success: true, ..Default::default() }]).await,
+        Err(e) => report_error("register", format!("register failed: {:?}", e)).await,
+    }
+}
+
+pub async fn logout(client: &Client) {
+    match client.logout().await {
+        Ok(_) => emit(&[CommandRecord { command: "logout".to_string(), success: true, ..Default::default() }]).await,
+        Err(e) => report_error("logout", format!("logout failed: {:?}", e)).await,
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct WhoAmI {
+    profile: String,
+    user: Option<String>,
+    issuer: Option<String>,
+    issued_at: Option<String>,
+    expires_at: Option<String>,
+    remaining_secs: Option<i64>,
+    /// Endpoint of the last connection, which may be a failover.
+    server: Option<String>,
+}
+
+pub async fn whoami(client: &Client) {
+    let profile = client.config().profile.clone();
+    let block = client.block();
+    let user = match client.user_name() {
+        Some(user) => user,
+        None => {
+            if is_structured() {
+                emit(&[WhoAmI { profile, user: None, issuer: None, issued_at: None, expires_at: None, remaining_secs: None, server: None }]).await;
+            } else {
+                async_print(format!("not logged in (profile {})", profile)).await;
+            }
+            return;
+        }
+    };
+
+    let claims = match block.claims() {
+        Ok(claims) => claims,
+        Err(e) => {
+            report_error("whoami", format!("decode token failed: {:?}", e)).await;
+            return;
+        }
+    };
+
+    let local_time = |secs: i64| {
+        chrono::Local
+            .timestamp_opt(secs, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+    };
+    let exp = claims.exp.unwrap_or(block.exp);
+    let remaining = exp - chrono::Utc::now().timestamp();
+
+    let who = WhoAmI {
+        profile,
+        user: Some(claims.sub.unwrap_or(user)),
+        issuer: claims.iss,
+        issued_at: claims.iat.and_then(local_time),
+        expires_at: local_time(exp),
+        remaining_secs: Some(remaining),
+        server: client.server().map(|(endpoint, addr)| format!("{} ({})", endpoint, addr)),
+    };
+
+    if is_structured() {
+        emit(&[who]).await;
+        return;
+    }
+
+    let unknown = || "unknown".to_string();
+    let remaining = if remaining > 0 { format_duration(remaining) } else { "expired".to_string() };
+    let mut lines = vec![
+        format!("user       {}", who.user.unwrap_or_else(unknown)),
+        format!("profile    {}", who.profile),
+        format!("issuer     {}", who.issuer.unwrap_or_else(unknown)),
+        format!("issued at  {}", who.issued_at.unwrap_or_else(unknown)),
+        format!("expires at {}", who.expires_at.unwrap_or_else(unknown)),
+        format!("remaining  {}", remaining),
+    ];
+    if let Some(server) = who.server {
+        lines.push(format!("server     {}", server));
+    }
+    async_print(lines.join("\n")).await;
+}
+
+pub async fn delete(client: &Client, args: Args) {
+    let pick = match take_pick(client, "delete", &args).await {
+        Some(pick) => pick,
+        None => return,
+    };
 
-    let resp = user::login::login(block, user_name.clone(), passwd).await;
+    let file_id = match resolve_file_id(client, "delete", args.arg("file"), pick).await {
+        Some(file_id) => file_id,
+        None => return,
+    };
+
+    let resp = client.delete(file_id).await;
     match resp {
         Ok(_) => {
-            Some(user_name)
+            let record = CommandRecord {
+                command: "delete".to_string(),
+                success: true,
+                file_id: Some(file_id),
+                ..Default::default()
+            };
+            report("success".to_string(), &record).await;
         },
         Err(e) => {
-            async_print(format!("login failed: {:?}", e)).await;
-            None
+            report_error("delete", format!("delete file failed: {:?}", e)).await;
         }
     }
 }
 
-pub async fn register(block: &mut ControlBlock, args: Option<Vec<String>>) -> Option<String> {
-    let (user_name, passwd) = match args {
-        Some(args) => {
-            if args.len() < 2 {
-                help(Some(vec!["register".to_string()])).await;
-                return None;
-            }
-            (args[0].to_owned(), args[1].to_owned())
-        },
-        None => {
-            help(Some(vec!["register".to_string()])).await;
-            return None;
-        },
+pub async fn download(client: &Client, args: Args) {
+    let pick = match take_pick(client, "download", &args).await {
+        Some(pick) => pick,
+        None => return,
+    };
+    let transfer = match take_limit("download", &args).await {
+        Some(throttle) => with_progress(throttle),
+        None => return,
+    };
+    let policy = match args.last_of(&["--overwrite", "--skip-existing", "--rename"]) {
+        Some("--overwrite") => OverwritePolicy::Overwrite,
+        Some("--skip-existing") => OverwritePolicy::Skip,
+        Some(_) => OverwritePolicy::Rename,
+        None => client.config().overwrite,
     };
+    let target_path = args.arg("file_path").to_string();
 
-    let resp = user::login::register(block, user_name.clone(), passwd).await;
+    let file_id = match resolve_file_id(client, "download", args.arg("file"), pick).await {
+        Some(file_id) => file_id,
+        None => return,
+    };
+
+    let resp = if target_path == "-" {
+        // keep the result message off the pipe as well
+        output::reserve_stdout(true);
+        client
+            .download_stream(file_id, &mut tokio::io::stdout(), &transfer)
+            .await
+            .map(|_| Some(target_path.clone()))
+    } else {
+        client.download(file_id, &target_path, None, policy, &transfer).await
+    };
     match resp {
-        Ok(_) => {
-            Some(user_name)
+        Ok(Some(path)) => {
+            let record = CommandRecord {
+                command: "download".to_string(),
+                success: true,
+                file_id: Some(file_id),
+                path: Some(path.clone()),
+                ..Default::default()
+            };
+            report(format!("download file success: {}", path), &record).await;
+        },
+        Ok(None) => {
+            let record = CommandRecord {
+                command: "download".to_string(),
+                success: true,
+                file_id: Some(file_id),
+                error: Some("skipped, target exists".to_string()),
+                ..Default::default()
+            };
+            report("target exists, download skipped".to_string(), &record).await;
         },
         Err(e) => {
-            async_print(format!("register failed: {:?}", e)).await;
+            report_error("download", format!("download file failed: {:?}", e)).await;
+        }
+    }
+    output::reserve_stdout(false);
+}
+
+/// The `--pick mode` of `args`, falling back to the configured mode.
+/// Returns `None` after reporting a bad value.
+async fn take_pick(client: &Client, command: &str, args: &Args) -> Option<Disambiguation> {
+    match args.value_as("--pick", str::parse::<Disambiguation>) {
+        Ok(Some(pick)) => Some(pick),
+        Ok(None) => Some(client.config().disambiguation),
+        Err(e) => {
+            report_error(command, e).await;
             None
         }
     }
 }
 
-pub async fn delete(block: ControlBlock, args: Option<Vec<String>>) {
-    let file_id = match args {
-        Some(args) => {
-            if args.len() < 1 {
-                help(Some(vec!["delete".to_string()])).await;
-                return;
-            }
-            args[0].to_owned()
-        },
-        None => {
-            help(Some(vec!["delete".to_string()])).await;
-            return;
+/// The `--limit rate` of `args`; transfers without one only follow the global limit.
+/// Returns `None` after reporting a bad value.
+async fn take_limit(command: &str, args: &Args) -> Option<Throttle> {
+    match args.value_as("--limit", parse_rate) {
+        Ok(rate) => Some(rate.map_or_else(Throttle::default, Throttle::new)),
+        Err(e) => {
+            report_error(command, e).await;
+            None
         }
+    }
+}
+
+/// A transfer showing its progress on stderr when that is a terminal; records and piped data stay clean.
+fn with_progress(throttle: Throttle) -> Transfer {
+    if is_structured() || !std::io::stderr().is_terminal() {
+        return Transfer { throttle, ..Default::default() };
+    }
+
+    let progress = Progress::new(|done, total| {
+        let line = match total {
+            0 => format_size(done),
+            _ => format!("{} / {} ({}%)", format_size(done), format_size(total), done * 100 / total),
+        };
+        eprint!("\r\x1B[K{}", line);
+    });
+    Transfer { throttle, progress }
+}
+
+/// Turns a numeric id, file name, glob or `name@latest` into a single file id.
+async fn resolve_file_id(client: &Client, command: &str, spec: &str, pick: Disambiguation) -> Option<FileId> {
+    if let Ok(file_id) = spec.parse::<FileId>() {
+        return Some(file_id);
+    }
+
+    let pick = if spec.ends_with(file::info::LATEST_SUFFIX) {
+        Disambiguation::Newest
+    } else {
+        pick
     };
-    
-    let file_id: i32 = match file_id.parse() {
-        Ok(file_id) => file_id,
+
+    let files = match client.find(spec).await {
+        Ok(files) => files,
         Err(e) => {
-            async_print(format!("illegal file_id: {:?}", e)).await;
+            report_error(command, format!("find file {} failed: {:?}", spec, e)).await;
+            return None;
+        }
+    };
+
+    let describe = |index: usize, file: &FileInfo| {
+        format!(
+            "[{}] id {} {} {} {}",
+            index + 1,
+            file.id,
+            file.file_name,
+            format_size(file.file_size.get()),
+            file.created_at.format("%Y-%m-%d %H:%M:%S")
+        )
+    };
+
+    match (files.len(), pick) {
+        (0, _) => {
+            report_error(command, format!("no file matches {}", spec)).await;
+            None
+        },
+        (1, _) | (_, Disambiguation::Newest) => Some(files[0].id),
+        (_, Disambiguation::Error) => {
+            let candidates = files.iter().enumerate().map(|(i, file)| describe(i, file)).collect::<Vec<_>>();
+            report_error(
+                command,
+                format!(
+                    "{} files match {}, use a file id, {}@latest or --pick newest|ask:\n{}",
+                    files.len(),
+                    spec,
+                    spec.strip_suffix(file::info::LATEST_SUFFIX).unwrap_or(spec),
+                    candidates.join("\n")
+                ),
+            ).await;
+            None
+        },
+        (_, Disambiguation::Ask) => {
+            let candidates = files.iter().enumerate().map(|(i, file)| describe(i, file)).collect::<Vec<_>>();
+            async_print(candidates.join("\n")).await;
+            let answer = read_answer(format!("pick 1-{} > ", files.len())).await?;
+            match answer.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= files.len() => Some(files[n - 1].id),
+                _ => {
+                    report_error(command, format!("illegal choice: {}", answer)).await;
+                    None
+                }
+            }
+        },
+    }
+}
+
+pub async fn upload(client: &Client, args: Args) {
+    let transfer = match take_limit("upload", &args).await {
+        Some(throttle) => with_progress(throttle),
+        None => return,
+    };
+    let (file_name, path) = match (args.arg("file_name"), args.opt_arg("path"), args.value("--name")) {
+        ("-", None, Some(file_name)) => (file_name.to_string(), "-".to_string()),
+        (file_name, Some(path), None) if file_name != "-" => (file_name.to_string(), path.to_string()),
+        _ => {
+            help(Some("upload")).await;
             return;
         }
     };
 
-    let resp = file::info::delete_file(block.clone(), file_id).await;
+    let resp = if path == "-" {
+        client.upload_stream(&mut tokio::io::stdin(), &file_name, &transfer).await
+    } else {
+        client.upload(&format!("{}/{}", path, file_name), &file_name, &transfer).await
+    };
     match resp {
-        Ok(_) => {
-            async_print(format!("success")).await;
+        Ok(file_id) => {
+            let record = CommandRecord {
+                command: "upload".to_string(),
+                success: true,
+                file_id: Some(file_id),
+                file_name: Some(file_name),
+                path: Some(path),
+                ..Default::default()
+            };
+            report("upload file success".to_string(), &record).await;
         },
         Err(e) => {
-            async_print(format!("delete file failed: {:?}", e)).await;
+            report_error("upload", format!("upload file failed: {:?}", e)).await;
         }
     }
 }
 
-pub async fn download(block: ControlBlock, args: Option<Vec<String>>) {
-    let (file_id, target_path) = match args {
-        Some(args) => {
-            if args.len() < 2 {
-                help(Some(vec!["download".to_string()])).await;
+pub async fn list_file(client: &Client, args: Args) {
+    let filter = args.opt_arg("filter").unwrap_or_default();
+    let reverse = args.has("--reverse");
+    let long = args.has("--long");
+    let parsed = (|| {
+        let sort = args.value_as("--sort", str::parse::<file::info::SortKey>)?.unwrap_or_default();
+        let limit = args.value_as("--limit", str::parse::<usize>)?.unwrap_or(usize::MAX);
+        let offset = args.value_as("--offset", str::parse::<usize>)?.unwrap_or(0);
+        Ok::<_, String>((sort, limit, offset))
+    })();
+    let (sort, limit, offset) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            report_error("list_file", e).await;
+            return;
+        }
+    };
+
+    let resp = client.list(filter).await;
+    match resp {
+        Ok(files) => {
+            #[derive(Tabled)]
+            struct FileInfoDisplay {
+                file_id: FileId,
+                file_name: String,
+                file_size: String,
+                status: i32,
+                upload_time: String,
+            }
+
+            #[derive(Tabled)]
+            struct FileInfoLongDisplay {
+                file_id: FileId,
+                file_name: String,
+                file_size: String,
+                bytes: ByteSize,
+                status: i32,
+                checksum: String,
+                upload_time: String,
+            }
+
+            let total = files.len();
+            let files = file::info::page_files(files, sort, reverse, offset, limit);
+            let shown = files.len();
+
+            if is_structured() {
+                emit(&files).await;
                 return;
             }
-            (args[0].to_owned(), args[1].to_owned())
+
+            let table = if long {
+                Table::new(files.iter().map(|file_info| {
+                    FileInfoLongDisplay {
+                        file_id: file_info.id,
+                        file_name: file_info.file_name.clone(),
+                        file_size: format_size(file_info.file_size.get()),
+                        bytes: file_info.file_size,
+                        status: file_info.file_status,
+                        checksum: format!("{:08x}", file_info.file_checksum),
+                        upload_time: file_info.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    }
+                }))
+            } else {
+                Table::new(files.iter().map(|file_info| {
+                    FileInfoDisplay {
+                        file_id: file_info.id,
+                        file_name: file_info.file_name.clone(),
+                        file_size: format_size(file_info.file_size.get()),
+                        status: file_info.file_status,
+                        upload_time: file_info.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    }
+                }))
+            };
+
+            let summary = if shown < total {
+                format!("showing {} of {} files (offset {})", shown, total, offset)
+            } else {
+                format!("{} files", total)
+            };
+
+            page(format!("{}\n{}", table, summary)).await;
         },
-        None => {
-            help(Some(vec!["download".to_string()])).await;
+        Err(e) => {
+            report_error("list_file", format!("list file failed: {:?}", e)).await;
+        }
+    }
+}
+
+pub async fn sync(client: &Client, args: Args) {
+    let local_dir = args.arg("local_dir").to_string();
+    let remote_prefix = args.value("--remote-prefix").unwrap_or_default();
+    let dry_run = args.has("--dry-run");
+    let policy = match args.value_as("--policy", str::parse::<ConflictPolicy>) {
+        Ok(policy) => policy.unwrap_or_default(),
+        Err(e) => {
+            report_error("sync", e).await;
             return;
         }
     };
+    let throttle = match take_limit("sync", &args).await {
+        Some(throttle) => throttle,
+        None => return,
+    };
 
-    let file_id: i32 = match file_id.parse() {
-        Ok(file_id) => file_id,
+    let resp = super::sync::sync(client, &local_dir, remote_prefix, policy, dry_run, with_progress(throttle)).await;
+    match resp {
+        // json/csv already got a record per action
+        Ok(_) if !is_structured() => async_print("sync finished".to_string()).await,
+        Ok(_) => {},
         Err(e) => {
-            async_print(format!("illegal file_id: {:?}", e)).await;
+            report_error("sync", format!("sync failed: {:?}", e)).await;
+        }
+    }
+}
+
+pub async fn watch(client: &Client, args: Args) {
+    let dir = args.arg("dir").to_string();
+    let archive = args.value("--archive");
+    let settle = match args.value_as("--settle", str::parse::<u64>) {
+        Ok(settle) => settle.unwrap_or(super::watch::DEFAULT_SETTLE_SECS),
+        Err(e) => {
+            report_error("watch", e).await;
             return;
         }
     };
+    let throttle = match take_limit("watch", &args).await {
+        Some(throttle) => throttle,
+        None => return,
+    };
 
-    let resp = file::download::download(block.clone(), file_id, &target_path).await;
+    let resp = super::watch::watch(client, &dir, archive, Duration::from_secs(settle), Transfer { throttle, ..Default::default() }).await;
     match resp {
         Ok(_) => {
-            async_print(format!("download file success")).await;
+            let record = CommandRecord {
+                command: "watch".to_string(),
+                success: true,
+                path: Some(dir),
+                ..Default::default()
+            };
+            report("watch stopped".to_string(), &record).await;
         },
         Err(e) => {
-            async_print(format!("download file failed: {:?}", e)).await;
+            report_error("watch", format!("watch failed: {:?}", e)).await;
         }
     }
 }
 
-pub async fn upload(block: ControlBlock, args: Option<Vec<String>>) {
-    let (file_name, path) = match args {
-        Some(args) => {
-            if args.len() < 2 {
-                help(Some(vec!["upload".to_string()])).await;
-                return;
-            }
-            (args[0].to_owned(), args[1].to_owned())
-        },
-        None => {
-            help(Some(vec!["upload".to_string()])).await;
+pub async fn set_output(args: Args) {
+    match args.arg("format").parse() {
+        Ok(format) => output::set_output_format(format),
+        Err(e) => report_error("output", e).await,
+    }
+}
+
+pub async fn info(client: &Client, args: Args) {
+    let pick = match take_pick(client, "info", &args).await {
+        Some(pick) => pick,
+        None => return,
+    };
+    let show_blocks = args.has("--blocks");
+
+    let file_id = match resolve_file_id(client, "info", args.arg("file"), pick).await {
+        Some(file_id) => file_id,
+        None => return,
+    };
+
+    let details = match client.details(file_id).await {
+        Ok(details) => details,
+        Err(e) => {
+            report_error("info", format!("get file info failed: {:?}", e)).await;
             return;
         }
     };
 
-    let resp = file::upload::upload(block, &file_name, path).await;
-    match resp {
-        Ok(_) => {
-            async_print(format!("upload file success")).await;
-        },
+    let distribution = file::info::size_distribution(&details.blocks);
+    let (summary, blocks) = match details.summary() {
+        Ok(summary) => summary,
         Err(e) => {
-            async_print(format!("upload file failed: {:?}", e)).await;
+            report_error("info", format!("get file info failed: {}", e)).await;
+            return;
+        }
+    };
+
+    if is_structured() {
+        emit(&[summary]).await;
+        if show_blocks {
+            emit(&blocks).await;
+        }
+        return;
+    }
+
+    let file_size = summary.info.file_size;
+    let mut lines = vec![
+        format!("file_id      {}", summary.info.id),
+        format!("file_name    {}", summary.info.file_name),
+        format!("file_size    {} ({} bytes)", format_size(file_size.get()), file_size),
+        format!("checksum     {:08x}", summary.info.file_checksum),
+        format!("status       {}", summary.info.file_status),
+        format!("created_at   {}", summary.info.created_at.format("%Y-%m-%d %H:%M:%S")),
+        format!(
+            "blocks       {} ({} unreadable, {} bad checksum)",
+            summary.block_count, summary.unreadable_blocks, summary.bad_checksum_blocks
+        ),
+    ];
+    if !summary.missing_blocks.is_empty() {
+        let missing = summary.missing_blocks.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        lines.push(format!("missing      {}", missing.join(", ")));
+    }
+    if let (Some(min), Some(max)) = (summary.min_block_size, summary.max_block_size) {
+        lines.push(format!("block size   min {} / max {}", format_size(min.get()), format_size(max.get())));
+        let distribution = distribution
+            .iter()
+            .map(|(size, count)| format!("{} x {}", format_size(size.get()), count))
+            .collect::<Vec<_>>();
+        lines.push(format!("distribution {}", distribution.join(", ")));
+    }
+    if summary.block_size_total != file_size {
+        lines.push(format!(
+            "block total  {} bytes, file size is {} bytes",
+            summary.block_size_total, file_size
+        ));
+    }
+
+    if show_blocks {
+        #[derive(Tabled)]
+        struct BlockDisplay {
+            block_id: BlockId,
+            id: BlockHandle,
+            block_name: String,
+            block_size: String,
+            checksum: String,
+            checksum_ok: bool,
+            created_at: String,
         }
+
+        let table = Table::new(blocks.iter().map(|report| BlockDisplay {
+            block_id: report.block.block_id,
+            id: report.block.id,
+            block_name: report.block.block_name.clone(),
+            block_size: format_size(report.block.block_size.get()),
+            checksum: format!("{:08x}", report.block.block_checksum),
+            checksum_ok: report.checksum_ok,
+            created_at: report.block.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }));
+        lines.push(table.to_string());
     }
+
+    page(lines.join("\n")).await;
 }
 
-pub async fn list_file(args: Option<Vec<String>>) {
-    let filter = match args {
-        Some(args) => {
-            if args.len() < 1 {
-                "".to_string()
-            } else {
-                args[0].clone()
-            }
-        },
-        None => "".to_string()
+pub async fn clean(client: &Client, args: Args) {
+    let older_than = match args.value_as("--older-than", parse_duration) {
+        Ok(older_than) => older_than.unwrap_or(Duration::from_secs(60 * 60)),
+        Err(e) => {
+            report_error("clean", e).await;
+            return;
+        }
     };
-    let resp = file::info::list_file(filter).await;
-    match resp {
-        Ok(resp) => {
-            #[derive(Tabled)]
-            struct FileInfoDisplay {
-                file_id: i32,
-                file_name: String,
-                file_size: i64,
-                upload_time: String,
-            }
 
-            let file_info = resp.file_info.iter().map(|file_info| {
-                FileInfoDisplay {
-                    file_id: file_info.id,
-                    file_name: file_info.file_name.clone(),
-                    file_size: file_info.file_size,
-                    upload_time: file_info.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
-                }
-            }).collect::<Vec<_>>();
+    let report = file::clean::clean(&client.config().state_dir, args.args("dir"), older_than).await;
 
-            let table = Table::new(file_info);
+    if is_structured() {
+        let records = report
+            .removed_files
+            .iter()
+            .map(|path| CommandRecord {
+                command: "clean".to_string(),
+                success: true,
+                path: Some(path.clone()),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        emit(&records).await;
+        return;
+    }
 
-            async_print(format!("{}", table)).await;
-        },
+    let mut lines = report.removed_files.iter().map(|path| format!("removed {}", path)).collect::<Vec<_>>();
+    lines.push(format!(
+        "removed {} temp files and {} journals, {} downloads still running",
+        report.removed_files.len(),
+        report.removed_journals,
+        report.skipped_active
+    ));
+    async_print(lines.join("\n")).await;
+}
+
+pub async fn tls_info(client: &Client) {
+    let info = match tls::tls_info(client.config()) {
+        Ok(info) => info,
         Err(e) => {
-            async_print(format!("list file failed: {:?}", e)).await;
+            report_error("tls-info", format!("tls handshake failed: {}", e)).await;
+            return;
         }
+    };
+
+    if is_structured() {
+        emit(&[info]).await;
+        return;
+    }
+
+    let mut lines = vec![
+        format!("server  {} ({})", info.server, info.address),
+        format!("version {}", info.version),
+        format!("cipher  {}", info.cipher),
+    ];
+    for (depth, cert) in info.chain.iter().enumerate() {
+        lines.push(format!("cert {}", depth));
+        lines.push(format!("  subject  {}", cert.subject));
+        lines.push(format!("  issuer   {}", cert.issuer));
+        lines.push(format!("  valid    {} - {}", cert.not_before, cert.not_after));
+        lines.push(format!("  key      {}", cert.fingerprint));
     }
+    async_print(lines.join("\n")).await;
 }
 
+#[derive(Serialize, Debug)]
+struct LimitInfo {
+    /// Bytes per second outside the schedule, 0 for unlimited.
+    rate: u64,
+    schedule: String,
+    /// The rate in force right now.
+    current: u64,
+}
+
+/// Shows or changes the global bandwidth limit; running transfers of every client pick up changes within a second.
+pub async fn limit(args: Args) {
+    let mut limits = limit::global_limits().await;
+
+    let rate = args.opt_arg("rate").map(parse_rate).transpose();
+    let schedule = args.value_as("--schedule", |schedule| match schedule {
+        "off" => Ok(Vec::new()),
+        schedule => limit::parse_schedule(schedule),
+    });
+    let (rate, schedule) = match (rate, schedule) {
+        (Ok(rate), Ok(schedule)) => (rate, schedule),
+        (Err(e), _) | (_, Err(e)) => {
+            report_error("limit", e).await;
+            return;
+        }
+    };
+    let changed = rate.is_some() || schedule.is_some();
+    if let Some(rate) = rate {
+        limits.rate = rate;
+    }
+    if let Some(schedule) = schedule {
+        limits.schedule = schedule;
+    }
+
+    if changed && let Err(e) = limit::set_global_limits(limits.clone()).await {
+        report_error("limit", format!("save limit failed: {:?}", e)).await;
+        return;
+    }
+
+    let info = LimitInfo {
+        rate: limits.rate,
+        schedule: limit::format_schedule(&limits.schedule),
+        current: limits.rate_at(chrono::Local::now().time()),
+    };
+    if is_structured() {
+        emit(&[info]).await;
+        return;
+    }
+
+    let mut lines = vec![format!("global limit {}", format_rate(info.rate))];
+    if !info.schedule.is_empty() {
+        lines.push(format!("schedule     {}", info.schedule));
+        lines.push(format!("now          {}", format_rate(info.current)));
+    }
+    async_print(lines.join("\n")).await;
+}
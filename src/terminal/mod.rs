@@ -1,54 +1,111 @@
-use std::process::exit;
+use std::{io::IsTerminal as _, process::{exit, Stdio}};
 
+use args::Schema;
+use client::Client;
 use dashmap::DashMap;
-use tokio::{io::AsyncWriteExt, sync::OnceCell};
-use crate::{control::ControlBlock, user::authorization::refresh};
+use tokio::{io::AsyncWriteExt, sync::{Mutex, OnceCell}};
+use zeroize::Zeroizing;
 use handler::*;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
 
+mod args;
 mod handler;
+pub mod output;
+mod sync;
+mod watch;
 
-pub async fn terminal() -> ! {
-    let mut block = ControlBlock::default();
-    let mut user: Option<String> = None;
+pub async fn terminal(client: Client) -> ! {
+    client.restore_session().await;
 
     loop {
 
-        if let Some(_) = user {
-            if let Err(e) = refresh(&mut block).await {
-                panic!("refresh token failed: {:?}", e);
-            }
+        // the last command may have refreshed the token during a long transfer; a failure here,
+        // e.g. the network being down for a moment, is reported and the next command may retry
+        if let Err(e) = client.refresh().await {
+            output::report_error("refresh", format!("refresh token failed: {:?}", e)).await;
         }
 
-        let (cmd, args) = input(user.clone()).await;
-
-        match cmd.as_str() {
-            "help" => help(args).await,
-            "exit" => exit(0),
-            "login" => {
-                user = login(&mut block, args).await;
-            },
-            "register" => {
-                user = register(&mut block, args).await;
-            },
-            "delete" => delete(block.clone(), args).await,
-            "download" => download(block.clone(), args).await,
-            "upload" => upload(block.clone(), args).await,
-            "list_file" => list_file(args).await,
-            "" => continue,
-            _ => println!("unknown command: {}", cmd),
-        }
+        let (cmd, args) = input(client.user_name()).await;
+
+        dispatch(&client, cmd, args).await;
     }
 }
 
-pub async fn help(args: Option<Vec<String>>) {
+/// Runs a single command given on the command line, exiting non-zero if it reported an error.
+pub async fn run_once(client: Client, cmd: String, args: Vec<String>) -> ! {
+    client.restore_session().await;
+
+    dispatch(&client, cmd, args).await;
+
+    exit(if output::take_failed() { 1 } else { 0 })
+}
+
+async fn dispatch(client: &Client, cmd: String, words: Vec<String>) {
+    if cmd.is_empty() {
+        return;
+    }
+    output::begin_command();
+    let args = match schema(&cmd).map(|schema| schema.parse(words)) {
+        Some(Ok(args)) => args,
+        Some(Err(e)) => {
+            output::report_error(&cmd, e).await;
+            help(Some(&cmd)).await;
+            return;
+        },
+        None => return output::report_error(&cmd, format!("unknown command: {}", cmd)).await,
+    };
+
+    match cmd.as_str() {
+        "help" => help(args.opt_arg("command")).await,
+        "exit" => exit(0),
+        "login" => login(client, args).await,
+        "register" => register(client, args).await,
+        "logout" => logout(client).await,
+        "whoami" | "session" => whoami(client).await,
+        "clean" => clean(client, args).await,
+        "tls-info" => tls_info(client).await,
+        "limit" => limit(args).await,
+        "delete" => delete(client, args).await,
+        "download" => download(client, args).await,
+        "upload" => upload(client, args).await,
+        "list_file" => list_file(client, args).await,
+        "info" => info(client, args).await,
+        "output" => set_output(args).await,
+        "sync" => sync(client, args).await,
+        "watch" => watch(client, args).await,
+        _ => unreachable!("{} has a schema but no handler", cmd),
+    }
+}
+
+/// The arguments of each command, matching its help line.
+fn schema(cmd: &str) -> Option<Schema> {
+    let schema = match cmd {
+        "help" => Schema::new(&["command?"], &[], &[]),
+        "exit" | "logout" | "whoami" | "session" | "tls-info" => Schema::new(&[], &[], &[]),
+        "login" | "register" => Schema::new(&["user_name", "password?"], &["--password-file"], &[]),
+        "clean" => Schema::new(&["dir..."], &["--older-than"], &[]),
+        "limit" => Schema::new(&["rate?"], &["--schedule"], &[]),
+        "delete" => Schema::new(&["file"], &["--pick"], &[]),
+        "download" => Schema::new(&["file", "file_path"], &["--pick", "--limit"], &["--overwrite", "--skip-existing", "--rename"]),
+        "upload" => Schema::new(&["file_name", "path?"], &["--name", "--limit"], &[]),
+        "list_file" => Schema::new(&["filter?"], &["--sort", "--limit", "--offset"], &["--reverse", "--long"]),
+        "info" => Schema::new(&["file"], &["--pick"], &["--blocks"]),
+        "output" => Schema::new(&["format"], &[], &[]),
+        "sync" => Schema::new(&["local_dir"], &["--remote-prefix", "--policy", "--limit"], &["--dry-run"]),
+        "watch" => Schema::new(&["dir"], &["--archive", "--settle", "--limit"], &[]),
+        _ => return None,
+    };
+    Some(schema)
+}
+
+pub async fn help(command: Option<&str>) {
     let infos = get_help_info().await;
-    if let Some(args) = args {
-        if let Some(info) = infos.get(&args[0]) {
+    if let Some(command) = command {
+        if let Some(info) = infos.get(command) {
             async_print(format!("{}", info.value())).await;
             return;
         } else {
-            async_print(format!("help info of {} not found", args[0])).await;
+            async_print(format!("help info of {} not found", command)).await;
             return;
         }
     }
@@ -65,19 +122,28 @@ static HELP_INFO: OnceCell<DashMap<String, String>> = OnceCell::const_new();
 async fn get_help_info() -> &'static DashMap<String, String> {
     HELP_INFO.get_or_init(|| async {
         let map = DashMap::new();
-        map.insert("help".to_string(), "help      [args]                 : print help info".to_string());
-        map.insert("delete".to_string(), "delete    [file_id]              : delete file from server".to_string());
-        map.insert("download".to_string(), "download  [file_id] [file_path]  : download file from server".to_string());
+        map.insert("help".to_string(), "help      [command]              : print help info, quote names with spaces like \"my file.txt\", $VAR and ~ are expanded".to_string());
+        map.insert("clean".to_string(), "clean     [dir...] [--older-than 1h] : remove temp files of failed or crashed downloads".to_string());
+        map.insert("delete".to_string(), "delete    [file] [--pick newest|ask|error] : delete file from server, file is an id, name, glob or name@latest".to_string());
+        map.insert("download".to_string(), "download  [file] [file_path|-] [--pick newest|ask|error] [--overwrite|--skip-existing|--rename] [--limit 5MB/s] : download file from server, file is an id, name, glob or name@latest, - writes to stdout".to_string());
         map.insert("exit".to_string(), "exit                             : exit terminal".to_string());
-        map.insert("list_file".to_string(), "list_file [filter]               : list file in server, using filter as searching keyword".to_string());
-        map.insert("login".to_string(), "login     [user_name] [password] : login to server".to_string());
-        map.insert("register".to_string(), "register  [user_name] [password] : register to server".to_string());
-        map.insert("upload".to_string(), "upload    [file_name] [path]     : upload file to server".to_string());
+        map.insert("info".to_string(), "info      [file] [--blocks] [--pick newest|ask|error] : show file and block details, fetches every block of the file".to_string());
+        map.insert("limit".to_string(), "limit     [rate|off] [--schedule 08:00-18:00=2MB/s,...|off] : show or set the global bandwidth limit, running transfers follow changes".to_string());
+        map.insert("list_file".to_string(), "list_file [filter] [--sort name|size|time] [--reverse] [--limit n] [--offset n] [--long] : list file in server, using filter as searching keyword".to_string());
+        map.insert("login".to_string(), "login     [user_name] [--password-file f] : login to server, asks for the password unless given by file or $CLIENT_PASSWORD".to_string());
+        map.insert("logout".to_string(), "logout                           : logout and delete the saved session of this profile".to_string());
+        map.insert("output".to_string(), "output    [table|json|csv]       : set output format of all commands".to_string());
+        map.insert("register".to_string(), "register  [user_name] [--password-file f] : register to server, asks for the password twice unless given by file or $CLIENT_PASSWORD".to_string());
+        map.insert("sync".to_string(), "sync      [local_dir] [--remote-prefix p] [--policy keep-both|prefer-local|prefer-remote] [--dry-run] [--limit 5MB/s] : two-way sync a folder with server, deletions on either side are propagated".to_string());
+        map.insert("whoami".to_string(), "whoami                           : show the logged in user, profile and token expiry, also available as session".to_string());
+        map.insert("watch".to_string(), "watch     [dir] [--archive subdir] [--settle secs] [--limit 5MB/s] : upload files as they appear in dir until Ctrl-C".to_string());
+        map.insert("tls-info".to_string(), "tls-info                         : connect to the server and show the TLS version, cipher and certificate chain".to_string());
+        map.insert("upload".to_string(), "upload    [file_name] [path] | upload - --name [file_name] [--limit 5MB/s] : upload file to server, - reads from stdin (spooled to a temp file unless the client runs with --deferred-presend)".to_string());
         map
     }).await
 }
 
-async fn input(user: Option<String>) -> (String, Option<Vec<String>>) {
+async fn input(user: Option<String>) -> (String, Vec<String>) {
     
     if let Some(user) = user {
         async_print(format!("{user} > ")).await;
@@ -85,30 +151,100 @@ async fn input(user: Option<String>) -> (String, Option<Vec<String>>) {
         async_print("user > ".to_string()).await;
     }
 
-    let stdin = tokio::io::stdin();
-    let mut reader = BufReader::new(stdin);
-    let mut input = String::new();
-    reader.read_line(&mut input).await.unwrap();
-    let input = input.trim();
-    let mut args = input.split_whitespace();
-    let cmd = args.next().unwrap_or("").to_string();
-    let args = args.map(|arg| arg.to_string()).collect::<Vec<_>>();
-    let args = if args.len() > 0 {
-        Some(args)
-    } else {
-        None
+    let Some(input) = read_line().await else {
+        // stdin closed, e.g. the end of a piped script
+        exit(0);
     };
     clear_terminal().await;
-    (cmd, args)
+    let mut args = match args::tokenize(&input) {
+        Ok(args) => args.into_iter(),
+        Err(e) => {
+            output::report_error("input", e).await;
+            return (String::new(), Vec::new());
+        },
+    };
+    let cmd = args.next().unwrap_or_default();
+    (cmd, args.collect())
+}
+
+/// Asks a follow-up question inside a command; `None` when stdin is closed.
+pub async fn read_answer(prompt: String) -> Option<String> {
+    async_print(prompt).await;
+    read_line().await.map(|answer| answer.trim().to_string())
+}
+
+/// Stdin is buffered once for the whole process, so lines read ahead for one prompt, e.g. the rest
+/// of a piped script, are still there for the next.
+static STDIN_LINES: OnceCell<Mutex<Lines<BufReader<Stdin>>>> = OnceCell::const_new();
+
+/// The next line of stdin; `None` when stdin is closed or unreadable.
+async fn read_line() -> Option<String> {
+    let lines = STDIN_LINES.get_or_init(|| async { Mutex::new(BufReader::new(tokio::io::stdin()).lines()) }).await;
+    lines.lock().await.next_line().await.ok().flatten()
+}
+
+/// Asks for a secret without echoing it; `None` when there is no terminal to ask on.
+pub async fn read_password(prompt: String) -> Option<Zeroizing<String>> {
+    let rst = tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt)).await;
+    match rst {
+        Ok(Ok(password)) => Some(Zeroizing::new(password)),
+        _ => None,
+    }
 }
 
 async fn clear_terminal() {
+    if output::is_structured() || !std::io::stdin().is_terminal() {
+        return;
+    }
     print!("\x1B[2J\x1B[1;1H");
 }
 
+/// Prints human-readable text; it goes to stderr in json/csv mode so stdout only carries records,
+/// and while stdout carries file data.
 pub async fn async_print(buffer: String) {
-    let mut stdout = tokio::io::stdout();
     let buffer = format!("\n{buffer}");
+    if output::is_structured() || output::stdout_reserved() {
+        let mut stderr = tokio::io::stderr();
+        stderr.write_all(buffer.as_bytes()).await.unwrap();
+        stderr.flush().await.unwrap();
+        return;
+    }
+    let mut stdout = tokio::io::stdout();
     stdout.write_all(buffer.as_bytes()).await.unwrap();
     stdout.flush().await.unwrap();
-}
\ No newline at end of file
+}
+
+/// Rows of the terminal on stdout; `None` when stdout is not a terminal.
+fn terminal_height() -> Option<usize> {
+    // SAFETY: `winsize` is plain old data and `size` outlives the call.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 || size.ws_row == 0 {
+        return None;
+    }
+    Some(size.ws_row as usize)
+}
+
+/// Prints `buffer` through `$PAGER` when stdout is a terminal and the text doesn't fit on screen.
+pub async fn page(buffer: String) {
+    let height = terminal_height().unwrap_or(40);
+    if !std::io::stdout().is_terminal() || buffer.lines().count() < height {
+        return async_print(buffer).await;
+    }
+
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut parts = pager.split_whitespace();
+    let child = match parts.next() {
+        Some(cmd) => tokio::process::Command::new(cmd).args(parts).stdin(Stdio::piped()).spawn(),
+        None => return async_print(buffer).await,
+    };
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return async_print(buffer).await,
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // the pager may quit before reading everything, that's fine
+        let _ = stdin.write_all(buffer.as_bytes()).await;
+    }
+    let _ = child.wait().await;
+}
@@ -0,0 +1,206 @@
+use std::{
+    str::FromStr,
+    sync::{atomic::{AtomicBool, AtomicU8, Ordering}, Mutex},
+};
+
+use client::FileId;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::terminal::async_print;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+static OUTPUT_FORMAT: AtomicU8 = AtomicU8::new(OutputFormat::Table as u8);
+
+pub fn output_format() -> OutputFormat {
+    match OUTPUT_FORMAT.load(Ordering::Relaxed) {
+        1 => OutputFormat::Json,
+        2 => OutputFormat::Csv,
+        _ => OutputFormat::Table,
+    }
+}
+
+pub fn set_output_format(format: OutputFormat) {
+    OUTPUT_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn is_structured() -> bool {
+    output_format() != OutputFormat::Table
+}
+
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// Marks stdout as carrying file data, sending all text and records to stderr meanwhile.
+pub fn reserve_stdout(reserved: bool) {
+    STDOUT_RESERVED.store(reserved, Ordering::Relaxed);
+}
+
+pub fn stdout_reserved() -> bool {
+    STDOUT_RESERVED.load(Ordering::Relaxed)
+}
+
+/// Columns of the CSV header last written for the running command.
+static CSV_COLUMNS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Starts the output of a new command, which gets its own CSV header.
+pub fn begin_command() {
+    CSV_COLUMNS.lock().unwrap().clear();
+}
+
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Marks the running command as failed without reporting anything, e.g. after it emitted its own error record.
+pub fn set_failed() {
+    FAILED.store(true, Ordering::Relaxed);
+}
+
+/// Whether an error was reported since the last call.
+pub fn take_failed() -> bool {
+    FAILED.swap(false, Ordering::Relaxed)
+}
+
+/// Result of a single command, e.g. a finished transfer.
+#[derive(Serialize, Debug, Default)]
+pub struct CommandRecord {
+    pub command: String,
+    pub success: bool,
+    pub file_id: Option<FileId>,
+    pub file_name: Option<String>,
+    pub path: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Prints `message` in table mode, otherwise emits `record`.
+pub async fn report<T: Serialize>(message: String, record: &T) {
+    if is_structured() {
+        emit(std::slice::from_ref(record)).await;
+    } else {
+        async_print(message).await;
+    }
+}
+
+/// Reports a failed command; structured modes get a record with `success: false`.
+pub async fn report_error(command: &str, message: String) {
+    set_failed();
+    let record = CommandRecord {
+        command: command.to_string(),
+        success: false,
+        error: Some(message.clone()),
+        ..Default::default()
+    };
+    report(message, &record).await;
+}
+
+/// Writes records as JSON lines or CSV; does nothing in table mode.
+pub async fn emit<T: Serialize>(records: &[T]) {
+    let values = records
+        .iter()
+        .map(|record| serde_json::to_value(record).unwrap_or(Value::Null))
+        .collect::<Vec<_>>();
+
+    let buffer = match output_format() {
+        OutputFormat::Table => return,
+        OutputFormat::Json => values.iter().map(|value| format!("{}\n", value)).collect(),
+        OutputFormat::Csv => to_csv(&values, &mut CSV_COLUMNS.lock().unwrap()),
+    };
+
+    if stdout_reserved() {
+        let mut stderr = tokio::io::stderr();
+        stderr.write_all(buffer.as_bytes()).await.unwrap();
+        stderr.flush().await.unwrap();
+        return;
+    }
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(buffer.as_bytes()).await.unwrap();
+    stdout.flush().await.unwrap();
+}
+
+/// Renders records as CSV with their fields as columns, in declaration order. A header is written
+/// before the first record and again only if a record has other fields than `columns`,
+/// the columns of the last header.
+fn to_csv(values: &[Value], columns: &mut Vec<String>) -> String {
+    let mut buffer = String::new();
+    for value in values {
+        let map = match value {
+            Value::Object(map) => map,
+            _ => continue,
+        };
+        if !map.keys().eq(columns.iter()) {
+            *columns = map.keys().cloned().collect();
+            buffer.push_str(&columns.iter().map(|key| csv_field(key)).collect::<Vec<_>>().join(","));
+            buffer.push('\n');
+        }
+
+        let row = map
+            .values()
+            .map(|field| match field {
+                Value::Null => String::new(),
+                Value::String(s) => csv_field(s),
+                other => csv_field(&other.to_string()),
+            })
+            .collect::<Vec<_>>();
+        buffer.push_str(&row.join(","));
+        buffer.push('\n');
+    }
+
+    buffer
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_csv() {
+        let record = |file_id, file_name: &str| CommandRecord {
+            command: "upload".to_string(),
+            success: true,
+            file_id: Some(FileId::new(file_id)),
+            file_name: Some(file_name.to_string()),
+            ..Default::default()
+        };
+        let mut columns = Vec::new();
+
+        // one header per command, in field order, even when records come one at a time
+        let first = serde_json::to_value(record(1, "a,b.txt")).unwrap();
+        let second = serde_json::to_value(record(2, "say \"hi\"")).unwrap();
+        assert_eq!(
+            to_csv(&[first], &mut columns),
+            "command,success,file_id,file_name,path,error\nupload,true,1,\"a,b.txt\",,\n"
+        );
+        assert_eq!(to_csv(&[second], &mut columns), "upload,true,2,\"say \"\"hi\"\"\",,\n");
+
+        // records of another shape start a new section
+        let other = serde_json::json!({"name": "x", "size": 3});
+        assert_eq!(to_csv(&[other], &mut columns), "name,size\nx,3\n");
+    }
+}
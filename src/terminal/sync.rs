@@ -0,0 +1,561 @@
+use std::{collections::{HashMap, HashSet}, str::FromStr, time::UNIX_EPOCH};
+
+use crc_fast::{checksum_file, CrcAlgorithm::Crc32IsoHdlc};
+use serde::{Deserialize, Serialize};
+
+use client::{core::biz::FileInfo, file::download::OverwritePolicy, Client, FileId, Transfer};
+
+use crate::terminal::{async_print, output::{self, emit, is_structured, report_error}};
+
+pub const SYNC_STATE_FILE: &str = ".sync_state.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    #[default]
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "prefer-local" => Ok(ConflictPolicy::PreferLocal),
+            "prefer-remote" => Ok(ConflictPolicy::PreferRemote),
+            _ => Err(format!("unknown conflict policy: {}", s)),
+        }
+    }
+}
+
+/// What was last seen on both sides when a file was in sync.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncEntry {
+    pub size: u64,
+    pub mtime: i64,
+    pub checksum: u32,
+    pub remote_id: FileId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncState {
+    /// The prefix `files` were synced against, missing in state files of older versions.
+    #[serde(default)]
+    pub remote_prefix: Option<String>,
+    pub files: HashMap<String, SyncEntry>,
+}
+
+/// Remote files under the sync prefix, keyed by the name with the prefix stripped.
+#[derive(Debug, Default)]
+pub struct RemoteListing {
+    pub files: HashMap<String, FileInfo>,
+    /// Whether every synced file missing from `files` was confirmed deleted on the server.
+    /// The listing is a keyword search, so a file missing from it may still be there.
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalFile {
+    pub size: u64,
+    pub mtime: i64,
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncAction {
+    /// Local file has no remote counterpart.
+    Upload { name: String },
+    /// Local file changed since the last sync; `remote_id` is the stale remote copy.
+    Update { name: String, remote_id: FileId },
+    /// Remote file is missing locally or changed since the last sync.
+    Download { name: String, remote_id: FileId },
+    /// Both sides hold the same content but the state database doesn't know yet.
+    Record { name: String, remote_id: FileId },
+    /// Both sides changed independently.
+    Conflict { name: String, remote_id: FileId },
+    /// Local file was deleted since the last sync and the remote copy is unchanged.
+    DeleteRemote { name: String, remote_id: FileId },
+    /// Remote file was deleted since the last sync and the local copy is unchanged.
+    DeleteLocal { name: String },
+}
+
+/// One action in json/csv output. Every action has the same fields, so a sync is a single CSV table.
+#[derive(Serialize, Debug)]
+struct SyncRecord<'a> {
+    action: &'static str,
+    name: &'a str,
+    remote_id: Option<FileId>,
+    dry_run: bool,
+    error: Option<String>,
+}
+
+impl SyncAction {
+    fn kind(&self) -> &'static str {
+        match self {
+            SyncAction::Upload { .. } => "upload",
+            SyncAction::Update { .. } => "update",
+            SyncAction::Download { .. } => "download",
+            SyncAction::Record { .. } => "record",
+            SyncAction::Conflict { .. } => "conflict",
+            SyncAction::DeleteRemote { .. } => "delete_remote",
+            SyncAction::DeleteLocal { .. } => "delete_local",
+        }
+    }
+
+    fn remote_id(&self) -> Option<FileId> {
+        match self {
+            SyncAction::Upload { .. } | SyncAction::DeleteLocal { .. } => None,
+            SyncAction::Update { remote_id, .. }
+            | SyncAction::Download { remote_id, .. }
+            | SyncAction::Record { remote_id, .. }
+            | SyncAction::Conflict { remote_id, .. }
+            | SyncAction::DeleteRemote { remote_id, .. } => Some(*remote_id),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            SyncAction::Upload { name }
+            | SyncAction::Update { name, .. }
+            | SyncAction::Download { name, .. }
+            | SyncAction::Record { name, .. }
+            | SyncAction::Conflict { name, .. }
+            | SyncAction::DeleteRemote { name, .. }
+            | SyncAction::DeleteLocal { name } => name,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            SyncAction::Upload { name } => format!("upload    {}", name),
+            SyncAction::Update { name, remote_id } => format!("update    {} (replaces remote {})", name, remote_id),
+            SyncAction::Download { name, remote_id } => format!("download  {} (remote {})", name, remote_id),
+            SyncAction::Record { name, .. } => format!("in sync   {}", name),
+            SyncAction::Conflict { name, remote_id } => format!("conflict  {} (remote {})", name, remote_id),
+            SyncAction::DeleteRemote { name, remote_id } => format!("delete    remote {} ({})", name, remote_id),
+            SyncAction::DeleteLocal { name } => format!("delete    local {}", name),
+        }
+    }
+}
+
+pub async fn sync(
+    client: &Client,
+    local_dir: &str,
+    remote_prefix: &str,
+    policy: ConflictPolicy,
+    dry_run: bool,
+    transfer: Transfer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = load_state(local_dir).await?;
+    // entries recorded against another prefix say nothing about this one
+    if state.remote_prefix.as_deref().is_some_and(|prefix| prefix != remote_prefix) {
+        state.files.clear();
+    }
+    let local = scan_local(local_dir, &state).await?;
+    let remote = scan_remote(client, remote_prefix, &state).await?;
+
+    let actions = plan(&local, &remote, &state, remote_prefix);
+    if !dry_run && state.remote_prefix.as_deref() != Some(remote_prefix) {
+        state.remote_prefix = Some(remote_prefix.to_string());
+        save_state(local_dir, &state).await?;
+    }
+    if actions.is_empty() {
+        async_print("nothing to sync".to_string()).await;
+        return Ok(());
+    }
+
+    for action in actions {
+        let action = resolve(action, policy);
+        if !is_structured() {
+            async_print(action.describe()).await;
+        }
+
+        let error = match dry_run {
+            true => None,
+            false => apply(client, local_dir, remote_prefix, &local, &mut state, &action, &transfer)
+                .await
+                .err()
+                .map(|e| format!("{:?}", e)),
+        };
+        match &error {
+            Some(e) if !is_structured() => report_error("sync", format!("sync {} failed: {}", action.name(), e)).await,
+            Some(_) => output::set_failed(),
+            None => {},
+        }
+        if is_structured() {
+            let record = SyncRecord { action: action.kind(), name: action.name(), remote_id: action.remote_id(), dry_run, error };
+            emit(&[record]).await;
+        }
+
+        if !dry_run {
+            save_state(local_dir, &state).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns a conflict into the transfer the policy asks for; `KeepBoth` stays a conflict.
+fn resolve(action: SyncAction, policy: ConflictPolicy) -> SyncAction {
+    match (action, policy) {
+        (SyncAction::Conflict { name, remote_id }, ConflictPolicy::PreferLocal) => SyncAction::Update { name, remote_id },
+        (SyncAction::Conflict { name, remote_id }, ConflictPolicy::PreferRemote) => SyncAction::Download { name, remote_id },
+        (action, _) => action,
+    }
+}
+
+async fn apply(
+    client: &Client,
+    local_dir: &str,
+    remote_prefix: &str,
+    local: &HashMap<String, LocalFile>,
+    state: &mut SyncState,
+    action: &SyncAction,
+    transfer: &Transfer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        SyncAction::Upload { name } => {
+            let remote_id = push(client, local_dir, remote_prefix, name, transfer).await?;
+            record(state, name, &local[name], remote_id);
+        },
+        SyncAction::Update { name, remote_id } => {
+            let new_id = push(client, local_dir, remote_prefix, name, transfer).await?;
+            client.delete(*remote_id).await?;
+            record(state, name, &local[name], new_id);
+        },
+        SyncAction::Download { name, remote_id } => {
+            client.download(*remote_id, local_dir, Some(name), OverwritePolicy::Overwrite, transfer).await?;
+            let file = stat_local(local_dir, name, None).await?;
+            record(state, name, &file, *remote_id);
+        },
+        SyncAction::Record { name, remote_id } => {
+            record(state, name, &local[name], *remote_id);
+        },
+        SyncAction::Conflict { name, remote_id } => {
+            // save the remote version next to the local file, which then goes up as the newest remote
+            // version; the old one stays on the server and the copy is never uploaded
+            let copy_name = conflict_copy_name(name, *remote_id);
+            let copy_path = client.download(*remote_id, local_dir, Some(&copy_name), OverwritePolicy::Rename, transfer).await?;
+            async_print(format!("kept remote version of {} as {}", name, copy_path.unwrap_or(copy_name))).await;
+            let new_id = push(client, local_dir, remote_prefix, name, transfer).await?;
+            record(state, name, &local[name], new_id);
+        },
+        SyncAction::DeleteRemote { name, remote_id } => {
+            client.delete(*remote_id).await?;
+            state.files.remove(name);
+        },
+        SyncAction::DeleteLocal { name } => {
+            tokio::fs::remove_file(format!("{}/{}", local_dir, name)).await?;
+            state.files.remove(name);
+        },
+    }
+
+    Ok(())
+}
+
+async fn push(
+    client: &Client,
+    local_dir: &str,
+    remote_prefix: &str,
+    name: &str,
+    transfer: &Transfer,
+) -> Result<FileId, Box<dyn std::error::Error>> {
+    let remote_name = format!("{}{}", remote_prefix, name);
+    client.upload(&format!("{}/{}", local_dir, name), &remote_name, transfer).await
+}
+
+fn record(state: &mut SyncState, name: &str, file: &LocalFile, remote_id: FileId) {
+    state.files.insert(name.to_string(), SyncEntry {
+        size: file.size,
+        mtime: file.mtime,
+        checksum: file.checksum,
+        remote_id,
+    });
+}
+
+fn conflict_copy_name(name: &str, remote_id: FileId) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}.remote-{}.{}", stem, remote_id, ext),
+        _ => format!("{}.remote-{}", name, remote_id),
+    }
+}
+
+/// Whether `name` was made by `conflict_copy_name`, possibly renamed further to ` (1)` and so on.
+fn is_conflict_copy(name: &str) -> bool {
+    name.split('.').skip(1).any(|part| {
+        part.strip_prefix("remote-")
+            .and_then(|id| id.split(' ').next())
+            .is_some_and(|id| id.parse::<FileId>().is_ok())
+    })
+}
+
+/// Decides what to do with every file name seen locally or remotely.
+/// A file missing on one side that was in sync before was deleted there, so the deletion is
+/// propagated unless the other side changed since, in which case the changed copy is restored.
+/// Deletions are only planned when the state was recorded against `remote_prefix` and the listing
+/// is complete; otherwise such files are left alone.
+pub fn plan(
+    local: &HashMap<String, LocalFile>,
+    remote: &RemoteListing,
+    state: &SyncState,
+    remote_prefix: &str,
+) -> Vec<SyncAction> {
+    let can_delete = remote.complete && state.remote_prefix.as_deref() == Some(remote_prefix);
+    let mut names = local.keys().chain(remote.files.keys()).cloned().collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let entry = state.files.get(&name);
+            match (local.get(&name), remote.files.get(&name)) {
+                (Some(file), None) => match entry {
+                    Some(entry) if file.checksum == entry.checksum => can_delete.then_some(SyncAction::DeleteLocal { name }),
+                    None if is_conflict_copy(&name) => None,
+                    _ => Some(SyncAction::Upload { name }),
+                },
+                (None, Some(info)) => match entry {
+                    Some(entry) if info.id == entry.remote_id => can_delete.then_some(SyncAction::DeleteRemote { name, remote_id: info.id }),
+                    _ => Some(SyncAction::Download { name, remote_id: info.id }),
+                },
+                (Some(file), Some(info)) => {
+                    let remote_id = info.id;
+                    match entry {
+                        None if file.checksum == info.file_checksum => Some(SyncAction::Record { name, remote_id }),
+                        None => Some(SyncAction::Conflict { name, remote_id }),
+                        Some(entry) => {
+                            let local_changed = file.checksum != entry.checksum;
+                            let remote_changed = remote_id != entry.remote_id;
+                            match (local_changed, remote_changed) {
+                                (false, false) => None,
+                                (true, false) => Some(SyncAction::Update { name, remote_id }),
+                                (false, true) if info.file_checksum == entry.checksum => Some(SyncAction::Record { name, remote_id }),
+                                (false, true) => Some(SyncAction::Download { name, remote_id }),
+                                (true, true) if file.checksum == info.file_checksum => Some(SyncAction::Record { name, remote_id }),
+                                (true, true) => Some(SyncAction::Conflict { name, remote_id }),
+                            }
+                        },
+                    }
+                },
+                (None, None) => None,
+            }
+        })
+        .collect()
+}
+
+async fn load_state(local_dir: &str) -> Result<SyncState, Box<dyn std::error::Error>> {
+    match tokio::fs::read(format!("{}/{}", local_dir, SYNC_STATE_FILE)).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SyncState::default()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+async fn save_state(local_dir: &str, state: &SyncState) -> Result<(), Box<dyn std::error::Error>> {
+    let data = serde_json::to_vec_pretty(state)?;
+    tokio::fs::write(format!("{}/{}", local_dir, SYNC_STATE_FILE), data).await?;
+    Ok(())
+}
+
+async fn scan_local(local_dir: &str, state: &SyncState) -> Result<HashMap<String, LocalFile>, Box<dyn std::error::Error>> {
+    let mut files = HashMap::new();
+    let mut dir_entries = tokio::fs::read_dir(local_dir).await?;
+    while let Some(entry) = dir_entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name == SYNC_STATE_FILE {
+            continue;
+        }
+
+        let file = stat_local(local_dir, &name, state.files.get(&name)).await?;
+        files.insert(name, file);
+    }
+
+    Ok(files)
+}
+
+/// Stats a local file, reusing the recorded checksum when size and mtime are unchanged.
+async fn stat_local(local_dir: &str, name: &str, entry: Option<&SyncEntry>) -> Result<LocalFile, Box<dyn std::error::Error>> {
+    let path = format!("{}/{}", local_dir, name);
+    let metadata = tokio::fs::metadata(&path).await?;
+    let size = metadata.len();
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    let checksum = match entry {
+        Some(entry) if entry.size == size && entry.mtime == mtime => entry.checksum,
+        _ => checksum_file(Crc32IsoHdlc, &path, None)? as u32,
+    };
+
+    Ok(LocalFile { size, mtime, checksum })
+}
+
+/// Lists remote files under `remote_prefix`. When several uploads share a name the newest one wins.
+/// Synced files missing from the listing are looked up one by one to tell whether it is complete.
+async fn scan_remote(client: &Client, remote_prefix: &str, state: &SyncState) -> Result<RemoteListing, Box<dyn std::error::Error>> {
+    let mut files: HashMap<String, FileInfo> = HashMap::new();
+    let mut listed = HashSet::new();
+    for info in client.list(remote_prefix).await? {
+        let name = match info.file_name.strip_prefix(remote_prefix) {
+            Some(name) if !name.is_empty() && !name.contains('/') => name.to_string(),
+            _ => continue,
+        };
+        listed.insert(info.id);
+        match files.get(&name) {
+            Some(existing) if existing.created_at >= info.created_at => {},
+            _ => {
+                files.insert(name, info);
+            },
+        }
+    }
+
+    let mut complete = true;
+    for entry in state.files.values().filter(|entry| !listed.contains(&entry.remote_id)) {
+        match client.info(entry.remote_id).await {
+            Ok(_) => {
+                complete = false;
+                break;
+            },
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(RemoteListing { files, complete })
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDateTime;
+    use client::ByteSize;
+
+    use super::*;
+
+    fn local_file(checksum: u32) -> LocalFile {
+        LocalFile { size: 1, mtime: 1, checksum }
+    }
+
+    fn remote_file(id: u32, checksum: u32) -> FileInfo {
+        FileInfo {
+            id: FileId::new(id),
+            file_name: String::new(),
+            file_size: ByteSize::new(1),
+            file_checksum: checksum,
+            file_status: 1,
+            created_at: NaiveDateTime::default(),
+        }
+    }
+
+    fn entry(remote_id: u32, checksum: u32) -> SyncEntry {
+        SyncEntry { size: 1, mtime: 1, checksum, remote_id: FileId::new(remote_id) }
+    }
+
+    fn listing(files: Vec<(&str, FileInfo)>, complete: bool) -> RemoteListing {
+        RemoteListing { files: files.into_iter().map(|(name, info)| (name.to_string(), info)).collect(), complete }
+    }
+
+    fn synced(files: Vec<(&str, SyncEntry)>) -> SyncState {
+        SyncState {
+            remote_prefix: Some("p/".to_string()),
+            files: files.into_iter().map(|(name, entry)| (name.to_string(), entry)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let local = HashMap::from([
+            ("new".to_string(), local_file(1)),
+            ("same".to_string(), local_file(2)),
+            ("edited".to_string(), local_file(30)),
+            ("both".to_string(), local_file(40)),
+            ("untracked".to_string(), local_file(5)),
+            ("both.remote-44.txt".to_string(), local_file(41)),
+        ]);
+        let remote = listing(vec![
+            ("same", remote_file(2, 2)),
+            ("edited", remote_file(3, 3)),
+            ("both", remote_file(44, 41)),
+            ("untracked", remote_file(5, 50)),
+            ("remote_only", remote_file(6, 6)),
+        ], true);
+        let state = synced(vec![("same", entry(2, 2)), ("edited", entry(3, 3)), ("both", entry(4, 4))]);
+
+        // the conflict copy stays local
+        let actions = plan(&local, &remote, &state, "p/");
+        assert_eq!(actions, vec![
+            SyncAction::Conflict { name: "both".to_string(), remote_id: FileId::new(44) },
+            SyncAction::Update { name: "edited".to_string(), remote_id: FileId::new(3) },
+            SyncAction::Upload { name: "new".to_string() },
+            SyncAction::Download { name: "remote_only".to_string(), remote_id: FileId::new(6) },
+            SyncAction::Conflict { name: "untracked".to_string(), remote_id: FileId::new(5) },
+        ]);
+    }
+
+    fn deletion_case(complete: bool) -> (HashMap<String, LocalFile>, RemoteListing, SyncState) {
+        let local = HashMap::from([
+            ("deleted_remotely".to_string(), local_file(1)),
+            ("edited_after_remote_delete".to_string(), local_file(20)),
+        ]);
+        let remote = listing(vec![
+            ("deleted_locally", remote_file(3, 3)),
+            ("replaced_after_local_delete", remote_file(44, 40)),
+        ], complete);
+        let state = synced(vec![
+            ("deleted_remotely", entry(1, 1)),
+            ("edited_after_remote_delete", entry(2, 2)),
+            ("deleted_locally", entry(3, 3)),
+            ("replaced_after_local_delete", entry(4, 4)),
+        ]);
+        (local, remote, state)
+    }
+
+    #[test]
+    fn test_plan_deletions() {
+        let (local, remote, state) = deletion_case(true);
+        let actions = plan(&local, &remote, &state, "p/");
+        assert_eq!(actions, vec![
+            SyncAction::DeleteRemote { name: "deleted_locally".to_string(), remote_id: FileId::new(3) },
+            SyncAction::DeleteLocal { name: "deleted_remotely".to_string() },
+            SyncAction::Upload { name: "edited_after_remote_delete".to_string() },
+            SyncAction::Download { name: "replaced_after_local_delete".to_string(), remote_id: FileId::new(44) },
+        ]);
+    }
+
+    #[test]
+    fn test_plan_incomplete_listing() {
+        // nothing is deleted when the listing may have missed files, or the state is for another prefix
+        let expected = vec![
+            SyncAction::Upload { name: "edited_after_remote_delete".to_string() },
+            SyncAction::Download { name: "replaced_after_local_delete".to_string(), remote_id: FileId::new(44) },
+        ];
+        let (local, remote, state) = deletion_case(false);
+        assert_eq!(plan(&local, &remote, &state, "p/"), expected);
+
+        let (local, remote, state) = deletion_case(true);
+        assert_eq!(plan(&local, &remote, &state, "q/"), expected);
+        let legacy = SyncState { remote_prefix: None, ..state };
+        assert_eq!(plan(&local, &remote, &legacy, "p/"), expected);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let conflict = SyncAction::Conflict { name: "a".to_string(), remote_id: FileId::new(1) };
+        assert_eq!(resolve(conflict.clone(), ConflictPolicy::KeepBoth), conflict);
+        assert_eq!(resolve(conflict.clone(), ConflictPolicy::PreferLocal), SyncAction::Update { name: "a".to_string(), remote_id: FileId::new(1) });
+        assert_eq!(resolve(conflict, ConflictPolicy::PreferRemote), SyncAction::Download { name: "a".to_string(), remote_id: FileId::new(1) });
+        assert_eq!(conflict_copy_name("a.txt", FileId::new(7)), "a.remote-7.txt");
+        assert_eq!(conflict_copy_name(".env", FileId::new(7)), ".env.remote-7");
+        assert!(is_conflict_copy("a.remote-7.txt"));
+        assert!(is_conflict_copy("a.remote-7 (1).txt"));
+        assert!(is_conflict_copy(".env.remote-7"));
+        assert!(!is_conflict_copy("a.remote-x.txt"));
+        assert!(!is_conflict_copy("remote-7"));
+    }
+}
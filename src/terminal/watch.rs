@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt as _;
+use inotify::{EventMask, Inotify, WatchMask};
+use tokio::io::AsyncWriteExt as _;
+
+use client::{Client, Transfer};
+
+use crate::terminal::{async_print, output::{report, report_error, CommandRecord}};
+
+pub const WATCH_LOG_FILE: &str = ".watch.log";
+pub const DEFAULT_SETTLE_SECS: u64 = 5;
+
+/// A file that has shown activity and is waiting to stop changing.
+struct Settling {
+    size: u64,
+    last_change: Instant,
+}
+
+/// Watches `dir` until Ctrl-C, uploading every file once it has been quiet for `settle`.
+/// Shipped files are moved to `dir/archive` when given and listed in `WATCH_LOG_FILE`.
+/// Files already in `dir` at startup are shipped too, unless the log says they were before.
+pub async fn watch(
+    client: &Client,
+    dir: &str,
+    archive: Option<&str>,
+    settle: Duration,
+    transfer: Transfer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(archive) = archive {
+        tokio::fs::create_dir_all(format!("{}/{}", dir, archive)).await?;
+    }
+
+    // watching can go on for days, long past the token we started with
+    let _keep_alive = client.keep_alive();
+
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO,
+    )?;
+    let mut events = inotify.into_event_stream([0u8; 4096])?;
+
+    // scan after the watch is set up so nothing written in between is missed
+    let mut pending = existing_files(dir).await?;
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+    async_print(format!("watching {}, press Ctrl-C to stop", dir)).await;
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let event = match event {
+                    Some(event) => event?,
+                    None => break,
+                };
+                if event.mask.contains(EventMask::ISDIR) {
+                    continue;
+                }
+                let name = match event.name.and_then(|name| name.into_string().ok()) {
+                    Some(name) if !name.starts_with('.') => name,
+                    _ => continue,
+                };
+                tracing::trace!(mask = ?event.mask, name, "watch event");
+                pending.insert(name, Settling { size: 0, last_change: Instant::now() });
+            },
+            _ = ticker.tick() => {
+                let ready = settled(dir, &mut pending, settle, Instant::now()).await;
+                for name in ready {
+                    ship(client, dir, archive, &name, &transfer).await;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// The files in `dir` that haven't been shipped yet, to settle like new ones.
+async fn existing_files(dir: &str) -> Result<HashMap<String, Settling>, Box<dyn std::error::Error>> {
+    let log = match tokio::fs::read_to_string(format!("{}/{}", dir, WATCH_LOG_FILE)).await {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let shipped = shipped_names(&log);
+
+    let mut pending = HashMap::new();
+    let mut dir_entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = dir_entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') && !shipped.contains(&name) => {
+                pending.insert(name, Settling { size: 0, last_change: Instant::now() });
+            },
+            _ => {},
+        }
+    }
+    Ok(pending)
+}
+
+/// Names of the files `WATCH_LOG_FILE` lists as shipped, from lines written by `ship`.
+fn shipped_names(log: &str) -> Vec<String> {
+    log.lines()
+        .filter_map(|line| {
+            // skip the `%Y-%m-%d %H:%M:%S` timestamp
+            let (_, line) = line.split_once(' ')?.1.split_once(' ')?;
+            let (name, _) = line.strip_prefix("shipped ")?.rsplit_once(" as file ")?;
+            Some(name.to_string())
+        })
+        .collect()
+}
+
+/// Returns the pending files whose size hasn't changed for `settle` as of `now`, removing them from `pending`.
+async fn settled(dir: &str, pending: &mut HashMap<String, Settling>, settle: Duration, now: Instant) -> Vec<String> {
+    let mut ready = Vec::new();
+    let mut gone = Vec::new();
+
+    for (name, state) in pending.iter_mut() {
+        let size = match tokio::fs::metadata(format!("{}/{}", dir, name)).await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => {
+                gone.push(name.clone());
+                continue;
+            }
+        };
+
+        if size != state.size {
+            state.size = size;
+            state.last_change = now;
+        } else if now.saturating_duration_since(state.last_change) >= settle {
+            ready.push(name.clone());
+        }
+    }
+
+    for name in gone.iter().chain(ready.iter()) {
+        pending.remove(name);
+    }
+
+    ready.sort();
+    ready
+}
+
+async fn ship(client: &Client, dir: &str, archive: Option<&str>, name: &str, transfer: &Transfer) {
+    let mut record = CommandRecord {
+        command: "watch".to_string(),
+        file_name: Some(name.to_string()),
+        path: Some(dir.to_string()),
+        ..Default::default()
+    };
+
+    let rst = client.upload(&format!("{}/{}", dir, name), name, transfer).await;
+    let line = match rst {
+        Ok(file_id) => {
+            record.success = true;
+            record.file_id = Some(file_id);
+            let mut line = format!("shipped {} as file {}", name, file_id);
+            if let Some(archive) = archive {
+                let from = format!("{}/{}", dir, name);
+                let to = format!("{}/{}/{}", dir, archive, name);
+                match tokio::fs::rename(&from, &to).await {
+                    Ok(_) => {
+                        record.path = Some(format!("{}/{}", dir, archive));
+                        line.push_str(&format!(", archived to {}", archive));
+                    },
+                    Err(e) => {
+                        record.error = Some(format!("archive failed: {}", e));
+                        line.push_str(&format!(", archive failed: {}", e));
+                    },
+                }
+            }
+            line
+        },
+        Err(e) => {
+            record.error = Some(format!("{:?}", e));
+            format!("upload {} failed: {:?}", name, e)
+        },
+    };
+
+    report(line.clone(), &record).await;
+    if let Err(e) = append_log(dir, &line).await {
+        report_error("watch", format!("write {} failed: {:?}", WATCH_LOG_FILE, e)).await;
+    }
+}
+
+async fn append_log(dir: &str, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}/{}", dir, WATCH_LOG_FILE))
+        .await?;
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    file.write_all(format!("{} {}\n", now, line).as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_settled() {
+        let dir = std::env::temp_dir().join(format!("client_watch_{}", uuid::Uuid::new_v4()));
+        let dir = dir.to_string_lossy().to_string();
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(format!("{}/a.log", dir), b"12").await.unwrap();
+
+        let start = Instant::now();
+        let settle = Duration::from_secs(5);
+        let mut pending = existing_files(&dir).await.unwrap();
+        pending.insert("gone".to_string(), Settling { size: 0, last_change: start });
+
+        // the first look sees the size change, a second one within `settle` still waits
+        assert!(settled(&dir, &mut pending, settle, start).await.is_empty());
+        assert!(!pending.contains_key("gone"));
+        assert!(settled(&dir, &mut pending, settle, start + Duration::from_secs(4)).await.is_empty());
+
+        // growing restarts the wait
+        tokio::fs::write(format!("{}/a.log", dir), b"1234").await.unwrap();
+        assert!(settled(&dir, &mut pending, settle, start + Duration::from_secs(6)).await.is_empty());
+        assert!(settled(&dir, &mut pending, settle, start + Duration::from_secs(10)).await.is_empty());
+        assert_eq!(settled(&dir, &mut pending, settle, start + Duration::from_secs(11)).await, vec!["a.log"]);
+        assert!(pending.is_empty());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_existing_files() {
+        let dir = std::env::temp_dir().join(format!("client_watch_{}", uuid::Uuid::new_v4()));
+        let dir = dir.to_string_lossy().to_string();
+        tokio::fs::create_dir_all(format!("{}/archive", dir)).await.unwrap();
+        for name in ["new.txt", "old file.txt", ".hidden"] {
+            tokio::fs::write(format!("{}/{}", dir, name), b"x").await.unwrap();
+        }
+        let log = "2026-01-02 03:04:05 shipped old file.txt as file 7, archive failed: busy\n\
+                   2026-01-02 03:04:06 upload new.txt failed: refused\n";
+        tokio::fs::write(format!("{}/{}", dir, WATCH_LOG_FILE), log).await.unwrap();
+
+        assert_eq!(shipped_names(log), vec!["old file.txt"]);
+        let pending = existing_files(&dir).await.unwrap();
+        assert_eq!(pending.keys().collect::<Vec<_>>(), vec!["new.txt"]);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,13 +1,146 @@
-use crate::{control::ControlBlock, core::biz};
+use std::{sync::RwLock, time::Duration};
 
-pub async fn refresh(block: &mut ControlBlock) -> Result<(), Box<dyn std::error::Error>> {
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{control::ControlBlock, core::{biz, client::Context}};
+
+/// Tokens are refreshed this long before they expire while a transfer runs.
+const REFRESH_MARGIN_SECS: i64 = 5 * 60;
+/// Wait before trying again when a background refresh fails, and at least between two refreshes.
+const RETRY_SECS: i64 = 30;
+
+/// The newest token of a client, shared with block tasks that were started with an older one.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    current: RwLock<Option<ControlBlock>>,
+    /// Serializes refreshes so concurrent block tasks refresh a token only once.
+    refreshing: Mutex<()>,
+}
+
+pub async fn refresh(ctx: &Context, block: &mut ControlBlock) -> Result<(), Box<dyn std::error::Error>> {
     let exp = block.exp;
     let now = chrono::Utc::now().timestamp();
     let threshold = chrono::Duration::seconds(60 * 60 * 12);
 
     if exp - now < threshold.num_seconds() {
-        biz::refresh(block).await
-    } else {
-        Ok(())
+        biz::refresh(ctx, block).await?;
+        share(ctx, block);
+    }
+    Ok(())
+}
+
+/// Publishes `block` as the token every running transfer should use from now on.
+pub fn share(ctx: &Context, block: &ControlBlock) {
+    *ctx.tokens().current.write().unwrap() = Some(block.clone());
+}
+
+/// Drops the shared token, e.g. on logout, so it isn't handed out as the newest one any more.
+pub fn forget(ctx: &Context) {
+    *ctx.tokens().current.write().unwrap() = None;
+}
+
+/// The newest token for a transfer that was started with `block`.
+pub fn current(ctx: &Context, block: &ControlBlock) -> ControlBlock {
+    match &*ctx.tokens().current.read().unwrap() {
+        Some(shared) if shared.exp >= block.exp => shared.clone(),
+        _ => block.clone(),
+    }
+}
+
+/// Refreshes `stale` after a request made with it failed.
+/// If another task already replaced it, the newer token is returned without asking the server again.
+pub async fn reauth(ctx: &Context, stale: &ControlBlock) -> Result<ControlBlock, Box<dyn std::error::Error>> {
+    let _guard = ctx.tokens().refreshing.lock().await;
+
+    let mut block = current(ctx, stale);
+    if block.jwt != stale.jwt {
+        return Ok(block);
+    }
+
+    biz::refresh(ctx, &mut block).await?;
+    share(ctx, &block);
+    Ok(block)
+}
+
+/// Keeps the token of a transfer fresh until dropped.
+pub struct KeepAlive(JoinHandle<()>);
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Refreshes the token in the background shortly before it expires, for as long as the guard lives.
+pub fn keep_alive(ctx: &Context, block: &ControlBlock) -> KeepAlive {
+    let ctx = ctx.clone();
+    let block = block.clone();
+
+    KeepAlive(tokio::task::spawn(async move {
+        let mut block = block;
+        let mut refreshed = false;
+        loop {
+            block = current(&ctx, &block);
+            let wait = refresh_wait(block.exp, chrono::Utc::now().timestamp(), refreshed);
+            if wait > 0 {
+                tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+                refreshed = false;
+                continue;
+            }
+
+            let rst = reauth(&ctx, &block).await.map_err(|e| format!("{:?}", e));
+            refreshed = rst.is_ok();
+            if let Err(e) = rst {
+                tracing::warn!("background refresh failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(RETRY_SECS as u64)).await;
+            }
+        }
+    }))
+}
+
+/// Seconds until a token expiring at `exp` is due for a refresh. Right after a refresh it is at
+/// least `RETRY_SECS`, or a server handing out tokens that live no longer than the margin,
+/// or the same token again, would be asked for a new one in a tight loop.
+fn refresh_wait(exp: i64, now: i64, refreshed: bool) -> i64 {
+    let wait = exp - REFRESH_MARGIN_SECS - now;
+    if refreshed { wait.max(RETRY_SECS) } else { wait }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::client::ClientConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_current() {
+        let ctx = Context::new(ClientConfig::default());
+        let old = ControlBlock { jwt: "old".to_string(), exp: 100 };
+        let new = ControlBlock { jwt: "new".to_string(), exp: 200 };
+
+        share(&ctx, &new);
+        assert_eq!(current(&ctx, &old).jwt, "new");
+        // tokens of another client are its own
+        assert_eq!(current(&Context::new(ClientConfig::default()), &old).jwt, "old");
+
+        // a later login wins over an older shared token
+        let later = ControlBlock { jwt: "later".to_string(), exp: 300 };
+        assert_eq!(current(&ctx, &later).jwt, "later");
+
+        // nothing is left to pick up after a logout
+        forget(&ctx);
+        assert_eq!(current(&ctx, &ControlBlock::default()).jwt, ControlBlock::default().jwt);
+        assert_eq!(current(&ctx, &old).jwt, "old");
+    }
+
+    #[test]
+    fn test_refresh_wait() {
+        let now = 1_000_000;
+        assert_eq!(refresh_wait(now + 3600, now, false), 3600 - REFRESH_MARGIN_SECS);
+        assert_eq!(refresh_wait(now + 3600, now, true), 3600 - REFRESH_MARGIN_SECS);
+        // due now, unless the token was just refreshed and still is
+        assert!(refresh_wait(now + 60, now, false) <= 0);
+        assert_eq!(refresh_wait(now + 60, now, true), RETRY_SECS);
+        assert_eq!(refresh_wait(now - 10, now, true), RETRY_SECS);
     }
 }
@@ -1,9 +1,30 @@
-use crate::{control::ControlBlock, core::biz};
+use zeroize::Zeroizing;
 
-pub async fn login(block: &mut ControlBlock, user_name: String, password: String) -> Result<(), Box<dyn std::error::Error>> {
-    biz::login(block, user_name, password).await
+use crate::{control::ControlBlock, core::{biz, client::Context}, user::{authorization, session}};
+
+/// The request takes its own copy of the password and wipes it once sent.
+pub async fn login(ctx: &Context, block: &mut ControlBlock, user_name: String, password: &Zeroizing<String>) -> Result<(), Box<dyn std::error::Error>> {
+    biz::login(ctx, block, user_name.clone(), password.to_string()).await?;
+    keep_session(ctx, &user_name, block).await;
+    Ok(())
+}
+
+pub async fn register(ctx: &Context, block: &mut ControlBlock, user_name: String, password: &Zeroizing<String>) -> Result<(), Box<dyn std::error::Error>> {
+    biz::register(ctx, block, user_name.clone(), password.to_string()).await?;
+    keep_session(ctx, &user_name, block).await;
+    Ok(())
 }
 
-pub async fn register(block: &mut ControlBlock, user_name: String, password: String) -> Result<(), Box<dyn std::error::Error>> {
-    biz::register(block, user_name, password).await
-}
\ No newline at end of file
+pub async fn logout(ctx: &Context, block: &mut ControlBlock) -> Result<(), Box<dyn std::error::Error>> {
+    *block = ControlBlock::default();
+    authorization::forget(ctx);
+    session::remove(ctx).await
+}
+
+/// A session that can't be saved only costs a login next time, so it is not an error.
+async fn keep_session(ctx: &Context, user_name: &str, block: &ControlBlock) {
+    authorization::share(ctx, block);
+    if let Err(e) = session::save(ctx, user_name, block).await {
+        tracing::warn!("save session failed: {:?}", e);
+    }
+}
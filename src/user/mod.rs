@@ -1,2 +1,3 @@
 pub mod authorization;
-pub mod login;
\ No newline at end of file
+pub mod login;
+pub mod session;
\ No newline at end of file
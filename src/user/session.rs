@@ -0,0 +1,221 @@
+use std::os::unix::fs::PermissionsExt as _;
+
+use base64::{engine::general_purpose, Engine as _};
+use openssl::{
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{control::ControlBlock, core::{biz, client::Context}, user::authorization};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub user_name: String,
+    pub block: ControlBlock,
+}
+
+/// A session encrypted with AES-256-GCM under the local key file.
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedSession {
+    nonce: String,
+    tag: String,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum SessionFile {
+    Sealed(SealedSession),
+    Plain(Session),
+}
+
+fn sessions_dir(ctx: &Context) -> String {
+    format!("{}/sessions", ctx.config().state_dir)
+}
+
+fn session_path(ctx: &Context) -> String {
+    format!("{}/{}.json", sessions_dir(ctx), ctx.config().profile)
+}
+
+/// Writes `contents` readable by the owner only. The directory must exist; its mode is left alone,
+/// since it may be one the user picked, e.g. for `--session-key`.
+async fn write_private(path: &str, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true).mode(0o600);
+    let mut file = options.open(path).await?;
+    // an existing file keeps its old mode on open, so tighten it explicitly
+    file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents).await?;
+    tokio::io::AsyncWriteExt::flush(&mut file).await?;
+    Ok(())
+}
+
+/// Loads the local session key, creating a random one on first use.
+async fn session_key(key_file: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match tokio::fs::read(key_file).await {
+        Ok(key) if key.len() == 32 => Ok(key),
+        Ok(_) => Err(Box::new(std::io::Error::other(format!("{} is not a 32 byte key", key_file)))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut key = vec![0; 32];
+            rand_bytes(&mut key)?;
+            write_private(key_file, &key).await?;
+            Ok(key)
+        },
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+fn seal(key: &[u8], session: &Session) -> Result<SealedSession, Box<dyn std::error::Error>> {
+    let mut nonce = [0; 12];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0; 16];
+    let data = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &[], &serde_json::to_vec(session)?, &mut tag)?;
+
+    Ok(SealedSession {
+        nonce: general_purpose::STANDARD.encode(nonce),
+        tag: general_purpose::STANDARD.encode(tag),
+        data: general_purpose::STANDARD.encode(data),
+    })
+}
+
+fn unseal(key: &[u8], sealed: &SealedSession) -> Result<Session, Box<dyn std::error::Error>> {
+    let nonce = general_purpose::STANDARD.decode(&sealed.nonce)?;
+    let tag = general_purpose::STANDARD.decode(&sealed.tag)?;
+    let data = general_purpose::STANDARD.decode(&sealed.data)?;
+    let plain = decrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &[], &data, &tag)?;
+    Ok(serde_json::from_slice(&plain)?)
+}
+
+/// Saves the session of the active profile, encrypted if a session key file is configured.
+pub async fn save(ctx: &Context, user_name: &str, block: &ControlBlock) -> Result<(), Box<dyn std::error::Error>> {
+    let session = Session {
+        user_name: user_name.to_string(),
+        block: block.clone(),
+    };
+
+    let file = match &ctx.config().session_key_file {
+        Some(key_file) => SessionFile::Sealed(seal(&session_key(key_file).await?, &session)?),
+        None => SessionFile::Plain(session),
+    };
+
+    // the sessions directory is the client's own, so keep other users out of it
+    let dir = sessions_dir(ctx);
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).await?;
+
+    write_private(&session_path(ctx), &serde_json::to_vec(&file)?).await
+}
+
+pub async fn load(ctx: &Context) -> Result<Option<Session>, Box<dyn std::error::Error>> {
+    let data = match tokio::fs::read(session_path(ctx)).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    match serde_json::from_slice(&data)? {
+        SessionFile::Plain(session) => Ok(Some(session)),
+        SessionFile::Sealed(sealed) => match &ctx.config().session_key_file {
+            Some(key_file) => Ok(Some(unseal(&session_key(key_file).await?, &sealed)?)),
+            None => Err(Box::new(std::io::Error::other("session is encrypted but no session key file is configured"))),
+        },
+    }
+}
+
+pub async fn remove(ctx: &Context) -> Result<(), Box<dyn std::error::Error>> {
+    match tokio::fs::remove_file(session_path(ctx)).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Loads the saved session and checks it is still accepted by refreshing it.
+/// A session the server rejects is deleted; one that couldn't be checked, e.g. while offline, is kept.
+pub async fn restore(ctx: &Context) -> Option<Session> {
+    let mut session = match load(ctx).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return None,
+        Err(e) => {
+            tracing::warn!("load session failed: {:?}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = biz::refresh(ctx, &mut session.block).await {
+        if is_rejected(e.as_ref()) {
+            tracing::info!("saved session rejected: {:?}", e);
+            eprintln!("saved session of {} has expired, please login again", session.user_name);
+            let _ = remove(ctx).await;
+        } else {
+            tracing::warn!("check saved session failed: {:?}", e);
+            eprintln!("could not check the saved session of {}, please login again or retry later", session.user_name);
+        }
+        return None;
+    }
+    authorization::share(ctx, &session.block);
+
+    if let Err(e) = save(ctx, &session.user_name, &session.block).await {
+        tracing::warn!("save session failed: {:?}", e);
+    }
+
+    Some(session)
+}
+
+/// Whether the server itself refused the token, rather than the request not getting through.
+fn is_rejected(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = [7; 32];
+        let session = Session {
+            user_name: "alice".to_string(),
+            block: ControlBlock { jwt: "a.b.c".to_string(), exp: 42 },
+        };
+
+        let sealed = seal(&key, &session).unwrap();
+        assert!(!sealed.data.contains("a.b.c"));
+
+        let opened = unseal(&key, &sealed).unwrap();
+        assert_eq!(opened.user_name, "alice");
+        assert_eq!(opened.block.jwt, "a.b.c");
+        assert_eq!(opened.block.exp, 42);
+
+        assert!(unseal(&[8; 32], &sealed).is_err());
+    }
+
+    #[test]
+    fn test_is_rejected() {
+        let rejected: Box<dyn std::error::Error> = Box::new(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "refresh rejected"));
+        assert!(is_rejected(rejected.as_ref()));
+
+        let offline: Box<dyn std::error::Error> = Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert!(!is_rejected(offline.as_ref()));
+        let garbled: Box<dyn std::error::Error> = serde_json::from_str::<Session>("{").unwrap_err().into();
+        assert!(!is_rejected(garbled.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn test_write_private() {
+        let dir = std::env::temp_dir().join(format!("client_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = dir.join("x.key").to_string_lossy().to_string();
+        write_private(&path, b"secret").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // the directory is the user's, not ours
+        assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o755);
+
+        assert!(write_private(&dir.join("missing/x.key").to_string_lossy(), b"secret").await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use crate::core::{GB, KB, MB};
+
+/// Formats a byte count with a binary unit suffix, e.g. `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    let bytes_f = bytes as f64;
+    if bytes >= GB as u64 {
+        format!("{:.1} GiB", bytes_f / GB as f64)
+    } else if bytes >= MB as u64 {
+        format!("{:.1} MiB", bytes_f / MB as f64)
+    } else if bytes >= KB as u64 {
+        format!("{:.1} KiB", bytes_f / KB as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// Parses sizes like `512K`, `5MB`, `1.5GiB` or a bare byte count; units are binary, as in `format_size`.
+/// Fractions of a byte are cut off, and a size that is not zero but would become zero is refused.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(pos) => s.split_at(pos),
+        None => (s, ""),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("illegal size: {}", s))?;
+    let unit = match unit.to_ascii_uppercase().trim_end_matches('B').trim_end_matches('I') {
+        "" => 1,
+        "K" => KB,
+        "M" => MB,
+        "G" => GB,
+        _ => return Err(format!("illegal size unit in {}, use K, M or G", s)),
+    };
+    let bytes = (number * unit as f64) as u64;
+    if bytes == 0 && number != 0.0 {
+        return Err(format!("{} is less than one byte", s));
+    }
+    Ok(bytes)
+}
+
+/// Parses rates like `5MB/s` or `500K`, in bytes per second; `0`, `off` and `unlimited` mean no limit.
+/// A rate below one byte per second is refused rather than taken as no limit.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    match s {
+        "off" | "unlimited" | "none" => Ok(0),
+        _ => parse_size(s.strip_suffix("/s").unwrap_or(s)),
+    }
+}
+
+/// Formats a rate from `parse_rate`, e.g. `5.0 MiB/s` or `unlimited`.
+pub fn format_rate(rate: u64) -> String {
+    match rate {
+        0 => "unlimited".to_string(),
+        _ => format!("{}/s", format_size(rate)),
+    }
+}
+
+/// Parses durations like `90s`, `30m`, `12h` or `7d`; a bare number means seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("illegal duration: {}", s))?;
+    let unit_secs: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("illegal duration unit in {}, use s, m, h or d", s)),
+    };
+    match number.checked_mul(unit_secs) {
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("duration out of range: {}", s)),
+    }
+}
+
+/// Formats a span of seconds like `2d 3h`, `3h 12m` or `45s`, keeping the two largest units.
+pub fn format_duration(secs: i64) -> String {
+    if secs < 0 {
+        return format!("-{}", format_duration(-secs));
+    }
+
+    let units = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")];
+    let parts = units
+        .iter()
+        .skip_while(|(value, _)| *value == 0)
+        .take(2)
+        .filter(|(value, _)| *value != 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>();
+
+    if parts.is_empty() { "0s".to_string() } else { parts.join(" ") }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172800)));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert_eq!(parse_duration("213503982334601d"), Ok(Duration::from_secs(213503982334601 * 86400)));
+        assert_eq!(parse_duration("213503982334602d"), Err("duration out of range: 213503982334602d".to_string()));
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(3600 + 12 * 60 + 5), "1h 12m");
+        assert_eq!(format_duration(2 * 86400 + 30), "2d");
+        assert_eq!(format_duration(-90), "-1m 30s");
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("5MB/s"), Ok(5 * MB as u64));
+        assert_eq!(parse_rate("512k"), Ok(512 * KB as u64));
+        assert_eq!(parse_rate("1.5GiB/s"), Ok(3 * GB as u64 / 2));
+        assert_eq!(parse_rate("1000"), Ok(1000));
+        assert_eq!(parse_rate("unlimited"), Ok(0));
+        assert_eq!(parse_rate("0"), Ok(0));
+        assert_eq!(parse_rate("0.0MB/s"), Ok(0));
+        assert_eq!(parse_rate("1.5"), Ok(1));
+        assert_eq!(parse_rate("0.5").unwrap_err(), "0.5 is less than one byte");
+        assert!(parse_rate("0.0000001K/s").is_err());
+        assert!(parse_rate("5TB/s").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert_eq!(format_rate(5 * MB as u64), "5.0 MiB/s");
+        assert_eq!(format_rate(0), "unlimited");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(16 * MB as u64), "16.0 MiB");
+        assert_eq!(format_size(5 * GB as u64), "5.0 GiB");
+    }
+}